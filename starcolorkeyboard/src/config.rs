@@ -0,0 +1,120 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde::Deserialize;
+
+pub const APP_NAME: &str = "starcolorkeyboard";
+
+const SYSTEM_DATA_DIR: &str = "/usr/share";
+
+fn home_dir() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// `$XDG_CONFIG_HOME/starcolorkeyboard`, falling back to `~/.config/starcolorkeyboard`
+pub fn config_dir() -> PathBuf {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| home_dir().join(".config"))
+        .join(APP_NAME)
+}
+
+/// Directories searched for layout files, the first match wins
+pub fn layout_dirs() -> Vec<PathBuf> {
+    vec![
+        config_dir().join("layouts"),
+        PathBuf::from(SYSTEM_DATA_DIR)
+            .join(APP_NAME)
+            .join("layouts"),
+    ]
+}
+
+/// `$XDG_DATA_HOME/starcolorkeyboard`, falling back to `~/.local/share/starcolorkeyboard`
+pub fn data_dir() -> PathBuf {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| home_dir().join(".local/share"))
+        .join(APP_NAME)
+}
+
+/// The words learned from typing
+pub fn user_dictionary_path() -> PathBuf {
+    data_dir().join("userdict")
+}
+
+/// The symbols last typed from the emoji panel
+pub fn recent_emoji_path() -> PathBuf {
+    data_dir().join("recent_emoji")
+}
+
+/// Directories searched for the word frequency dictionaries, the first match wins
+pub fn dictionary_dirs() -> Vec<PathBuf> {
+    vec![
+        config_dir().join("dictionaries"),
+        PathBuf::from(SYSTEM_DATA_DIR)
+            .join(APP_NAME)
+            .join("dictionaries"),
+    ]
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// xkb names of the layouts the layout key cycles through
+    pub layouts: Vec<String>,
+    /// xkb variants of the layouts by the layout name, like `"de": "nodeadkeys"`
+    pub variants: BTreeMap<String, String>,
+    /// xkb options of all the layouts, like `lv3:ralt_switch,compose:menu`
+    pub options: String,
+    /// label the keys with what the xkb keymap types instead of the layout file text
+    pub keymap_labels: bool,
+    /// milliseconds a key is held before it starts to repeat
+    pub repeat_delay: u64,
+    /// repeats per second of a held key, 0 disables the repeat
+    pub repeat_rate: u32,
+    /// type text through the input method of the compositor if it has one
+    pub input_method: bool,
+    /// show the keyboard only while a text input has the focus, false keeps the manual mode
+    pub auto_show: bool,
+    /// suggest the words of the dictionary in the title strip
+    pub prediction: bool,
+    /// add the navigation keys below the number block of the layouts without their own
+    pub navigation: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            layouts: vec!["us".to_string()],
+            variants: BTreeMap::new(),
+            options: String::new(),
+            keymap_labels: false,
+            repeat_delay: 600,
+            repeat_rate: 25,
+            input_method: true,
+            auto_show: true,
+            prediction: true,
+            navigation: false,
+        }
+    }
+}
+
+impl Config {
+    /// Read `config.json` from [config_dir], a missing or broken file gives the default config
+    pub fn load() -> Self {
+        let path = config_dir().join("config.json");
+        let Ok(content) = std::fs::read_to_string(&path) else {
+            return Config::default();
+        };
+        match serde_json::from_str(&content) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("{}: {err}, use the default config", path.display());
+                Config::default()
+            }
+        }
+    }
+}
@@ -1,4 +1,10 @@
-pub const EXCULDE_ZONE_RIGHT: f64 = 80_f64;
+pub const EXCULDE_ZONE_RIGHT: f64 = 200_f64;
 pub const EXCULDE_ZONE_TOP: f64 = 40_f64;
 
+/// How many suggestions the title strip shows
+pub const SUGGESTION_COUNT: usize = 3;
+
 pub const KEYBOARD_TITLE: &str = "Never give up your dream";
+
+/// xkb keycodes are the evdev keycodes plus 8
+pub const XKB_KEYCODE_OFFSET: u32 = 8;
@@ -1,6 +1,4 @@
-use crate::otherkeys;
-
-use super::State;
+use super::{keyboardlayouts::Purpose, State, TouchPoint};
 
 use wayland_client::{
     protocol::{
@@ -19,6 +17,11 @@ use wayland_protocols::xdg::xdg_output::zv1::client::{
     zxdg_output_v1::{self, ZxdgOutputV1},
 };
 
+use wayland_protocols_misc::zwp_input_method_v2::client::{
+    zwp_input_method_manager_v2::ZwpInputMethodManagerV2,
+    zwp_input_method_v2::{self, ZwpInputMethodV2},
+};
+
 use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::{
     zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1,
     zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1,
@@ -77,6 +80,10 @@ impl Dispatch<wl_registry::WlRegistry, ()> for State {
                 let virtual_keyboard_manager =
                     registry.bind::<ZwpVirtualKeyboardManagerV1, _, _>(name, version, qh, ());
                 state.virtual_keyboard_manager = Some(virtual_keyboard_manager);
+            } else if interface == ZwpInputMethodManagerV2::interface().name {
+                let input_method_manager =
+                    registry.bind::<ZwpInputMethodManagerV2, _, _>(name, version, qh, ());
+                state.input_method_manager = Some(input_method_manager);
             }
         }
     }
@@ -192,14 +199,16 @@ impl Dispatch<wl_shm_pool::WlShmPool, ()> for State {
 
 impl Dispatch<wl_buffer::WlBuffer, ()> for State {
     fn event(
-        _: &mut Self,
-        _: &wl_buffer::WlBuffer,
-        _: wl_buffer::Event,
+        state: &mut Self,
+        buffer: &wl_buffer::WlBuffer,
+        event: wl_buffer::Event,
         _: &(),
         _: &Connection,
-        _: &QueueHandle<Self>,
+        qh: &QueueHandle<Self>,
     ) {
-        // we ignore wl_buffer events in this example
+        if let wl_buffer::Event::Release = event {
+            state.release_buffer(buffer, qh);
+        }
     }
 }
 
@@ -265,13 +274,16 @@ impl Dispatch<wl_keyboard::WlKeyboard, ()> for State {
 
 impl Dispatch<wl_callback::WlCallback, ()> for State {
     fn event(
-        _state: &mut Self,
-        _proxy: &wl_callback::WlCallback,
-        _event: <wl_callback::WlCallback as Proxy>::Event,
+        state: &mut Self,
+        proxy: &wl_callback::WlCallback,
+        event: <wl_callback::WlCallback as Proxy>::Event,
         _data: &(),
         _conn: &Connection,
-        _qhandle: &QueueHandle<Self>,
+        qhandle: &QueueHandle<Self>,
     ) {
+        if let wl_callback::Event::Done { .. } = event {
+            state.frame_done(proxy, qhandle);
+        }
     }
 }
 
@@ -287,26 +299,12 @@ impl Dispatch<wl_pointer::WlPointer, ()> for State {
         match event {
             wl_pointer::Event::Button { state, .. } => match state {
                 WEnum::Value(wl_pointer::ButtonState::Pressed) => {
-                    if let Some(key) = wlstate.get_key_point() {
-                        if !otherkeys::is_unique_key(key) {
-                            wlstate.key_press(key);
-                        }
-                    }
+                    wlstate.pressed_key = wlstate.press_at(wlstate.position, false, qh);
                 }
                 WEnum::Value(wl_pointer::ButtonState::Released) => {
-                    if let Some(key) = wlstate.get_key_point() {
-                        if otherkeys::is_unique_key(key) {
-                            if key == otherkeys::CLOSE_KEYBOARD {
-                                wlstate.running = false;
-                            } else if key == otherkeys::MIN_KEYBOARD {
-                                wlstate.is_min = !wlstate.is_min;
-                                wlstate.min_keyboard();
-                            }
-                            return;
-                        }
-                        if wlstate.key_release(key) {
-                            wlstate.update_map(qh);
-                        }
+                    // NOTE: release the pressed key even if the pointer moved away from it
+                    if let Some(key) = wlstate.pressed_key.take() {
+                        wlstate.release_at(key, wlstate.position, qh);
                     }
                 }
                 _ => {}
@@ -324,6 +322,14 @@ impl Dispatch<wl_pointer::WlPointer, ()> for State {
                 ..
             } => {
                 wlstate.position = (surface_x, surface_y);
+                if let Some(key) = wlstate.pressed_key {
+                    if wlstate.get_key_point() != Some(key) {
+                        wlstate.stop_repeat_key(key);
+                    }
+                }
+            }
+            wl_pointer::Event::Leave { .. } => {
+                wlstate.stop_repeat();
             }
             _ => {}
         }
@@ -337,18 +343,59 @@ impl Dispatch<wl_touch::WlTouch, ()> for State {
         event: <wl_touch::WlTouch as Proxy>::Event,
         _data: &(),
         _conn: &Connection,
-        _qhandle: &QueueHandle<Self>,
+        qh: &QueueHandle<Self>,
     ) {
         match event {
-            wl_touch::Event::Down { x, y, .. } => {
-                wlstate.touch_pos = (x, y);
-                if let Some(key) = wlstate.get_key_touch() {
-                    wlstate.key_press(key);
+            wl_touch::Event::Down { id, x, y, .. } => {
+                let key = wlstate.press_at((x, y), true, qh);
+                wlstate.touch_points.insert(
+                    id,
+                    TouchPoint {
+                        position: (x, y),
+                        key,
+                    },
+                );
+            }
+            wl_touch::Event::Motion { id, x, y, .. } => {
+                let Some(point) = wlstate.touch_points.get_mut(&id) else {
+                    return;
+                };
+                point.position = (x, y);
+                let old_key = point.key;
+                let new_key = wlstate.pangoui.get_key((x, y));
+                // NOTE: slide between the normal keys, modifiers, unique keys and popups stay held
+                let slidable = |state: &State, key: Option<u32>| {
+                    key.is_some_and(|key| {
+                        state.is_normal_key(key) && state.pangoui.popup_key() != Some(key)
+                    })
+                };
+                if new_key == old_key || !slidable(wlstate, old_key) {
+                    return;
+                }
+                wlstate.release_at(old_key.unwrap(), (x, y), qh);
+                let key = if slidable(wlstate, new_key) {
+                    wlstate.press_at((x, y), true, qh)
+                } else {
+                    None
+                };
+                if let Some(point) = wlstate.touch_points.get_mut(&id) {
+                    point.key = key;
+                }
+            }
+            wl_touch::Event::Up { id, .. } => {
+                if let Some(TouchPoint {
+                    position,
+                    key: Some(key),
+                }) = wlstate.touch_points.remove(&id)
+                {
+                    wlstate.release_at(key, position, qh);
                 }
             }
-            wl_touch::Event::Up { .. } => {
-                if let Some(key) = wlstate.get_key_touch() {
-                    wlstate.key_press(key);
+            wl_touch::Event::Cancel => {
+                for (_, point) in std::mem::take(&mut wlstate.touch_points) {
+                    if let Some(key) = point.key {
+                        wlstate.cancel_key(key, qh);
+                    }
                 }
             }
             _ => {}
@@ -381,6 +428,9 @@ impl Dispatch<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1, ()> for State {
             surface.ack_configure(serial);
             let surface = state.base_surface.as_ref().unwrap();
             if let Some(ref buffer) = state.buffer {
+                if let Some(buffers) = state.buffers.as_mut() {
+                    buffers.attach(buffer);
+                }
                 surface.attach(Some(buffer), 0, 0);
                 surface.commit();
             }
@@ -411,3 +461,47 @@ impl Dispatch<ZwpVirtualKeyboardV1, ()> for State {
     ) {
     }
 }
+
+impl Dispatch<ZwpInputMethodManagerV2, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpInputMethodManagerV2,
+        _event: <ZwpInputMethodManagerV2 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpInputMethodV2, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwpInputMethodV2,
+        event: zwp_input_method_v2::Event,
+        _data: &(),
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        let Some(input_method) = state.input_method.as_mut() else {
+            return;
+        };
+        let was_active = input_method.is_active();
+        let was_purpose = input_method.purpose();
+        if !input_method.handle_event(event) {
+            state.input_method = None;
+            // NOTE: no more focus changes come, do not leave the keyboard collapsed
+            state.follow_text_input(true, qh);
+            state.set_purpose(Purpose::Normal, qh);
+            return;
+        }
+        let active = input_method.is_active();
+        let purpose = input_method.purpose();
+        if purpose != was_purpose {
+            state.set_purpose(purpose, qh);
+        }
+        if active != was_active {
+            state.follow_text_input(active, qh);
+        }
+    }
+}
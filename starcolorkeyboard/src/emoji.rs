@@ -0,0 +1,101 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::config;
+
+const EMOJI_CATEGORIES: &str = include_str!("../asserts/emoji.json");
+
+/// How many recently used symbols are kept
+const RECENT_MAX: usize = 40;
+
+/// A page group of the emoji panel
+#[derive(Deserialize, Debug, Clone)]
+pub struct EmojiCategory {
+    /// shown on the category tab
+    pub label: String,
+    pub symbols: Vec<String>,
+}
+
+/// The embedded categories
+pub fn categories() -> Vec<EmojiCategory> {
+    serde_json::from_str(EMOJI_CATEGORIES).expect("the embedded emoji categories should be valid")
+}
+
+/// The recently typed symbols, the last one first
+#[derive(Debug, Default)]
+pub struct Recent {
+    /// None keeps the list in memory only
+    path: Option<PathBuf>,
+    pub symbols: Vec<String>,
+}
+
+impl Recent {
+    /// Read [config::recent_emoji_path], one symbol per line
+    pub fn load() -> Self {
+        let path = config::recent_emoji_path();
+        let symbols = match std::fs::read_to_string(&path) {
+            Ok(content) => content
+                .lines()
+                .filter(|line| !line.is_empty())
+                .take(RECENT_MAX)
+                .map(str::to_string)
+                .collect(),
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    eprintln!("{}: {err}, start without recent symbols", path.display());
+                }
+                vec![]
+            }
+        };
+        Recent {
+            path: Some(path),
+            symbols,
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = self.path.as_ref() else {
+            return;
+        };
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let mut content = self.symbols.join("\n");
+            content.push('\n');
+            std::fs::write(path, content)
+        };
+        if let Err(err) = write() {
+            eprintln!(
+                "{}: {err}, the recent symbols are not saved",
+                path.display()
+            );
+        }
+    }
+
+    /// Move the symbol to the front
+    pub fn push(&mut self, symbol: &str) {
+        self.symbols.retain(|recent| recent != symbol);
+        self.symbols.insert(0, symbol.to_string());
+        self.symbols.truncate(RECENT_MAX);
+        self.save();
+    }
+}
+
+#[test]
+fn tst_recent() {
+    assert!(categories()
+        .iter()
+        .all(|category| !category.symbols.is_empty()));
+    let mut recent = Recent::default();
+    recent.push("😀");
+    recent.push("👍");
+    recent.push("😀");
+    assert_eq!(recent.symbols, vec!["😀", "👍"]);
+    for index in 0..RECENT_MAX {
+        recent.push(&index.to_string());
+    }
+    assert_eq!(recent.symbols.len(), RECENT_MAX);
+    assert_eq!(recent.symbols[0], (RECENT_MAX - 1).to_string());
+}
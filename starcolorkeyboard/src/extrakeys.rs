@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use xkbcommon::xkb;
+
+use crate::consts::XKB_KEYCODE_OFFSET;
+
+/// The highest keycode to bind, X clients know no keycodes above it
+const MAX_KEYCODE: xkb::Keycode = 255;
+
+/// How long the keymap keeps the bound characters after the last one was typed
+pub const RESTORE_DELAY: Duration = Duration::from_secs(1);
+
+/// Characters bound to the keycodes the keymap leaves unused, to type what the keymap cannot.
+/// The keymap with them is uploaded instead of the base one, like wtype does
+#[derive(Debug, Default)]
+pub struct ExtraKeys {
+    /// the unused keycodes with their names
+    spare: Vec<(xkb::Keycode, String)>,
+    /// the characters with the index of their spare keycode, the least recently typed first
+    bound: Vec<(char, usize)>,
+}
+
+/// The Latin-1 characters are their own keysyms, the others are in the Unicode keysym range
+fn keysym(character: char) -> xkb::Keysym {
+    let code = character as u32;
+    if (0x20..0x7f).contains(&code) || (0xa0..=0xff).contains(&code) {
+        code
+    } else {
+        0x0100_0000 | code
+    }
+}
+
+impl ExtraKeys {
+    pub fn new(keymap: &xkb::Keymap) -> Self {
+        let last = keymap.max_keycode().min(MAX_KEYCODE);
+        let spare = (keymap.min_keycode()..=last)
+            .filter(|&keycode| keymap.num_layouts_for_key(keycode) == 0)
+            .filter_map(|keycode| Some((keycode, keymap.key_get_name(keycode)?.to_string())))
+            .collect();
+        ExtraKeys {
+            spare,
+            bound: vec![],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bound.is_empty()
+    }
+
+    /// Give all the keycodes back, the base keymap is enough again
+    pub fn clear(&mut self) {
+        self.bound.clear();
+    }
+
+    /// The evdev keycode which types the character, and true if the character is new and the
+    /// keymap has to be uploaded again. None if there is no spare keycode
+    pub fn bind(&mut self, character: char) -> Option<(u32, bool)> {
+        if character.is_control() {
+            return None;
+        }
+        let (index, is_new) = match self.bound.iter().position(|(bound, _)| *bound == character) {
+            Some(position) => (self.bound.remove(position).1, false),
+            // NOTE: the keycodes are taken in order, the first ones are bound already
+            None if self.bound.len() < self.spare.len() => (self.bound.len(), true),
+            None if self.bound.is_empty() => return None,
+            // NOTE: the least recently typed character gives its keycode up
+            None => (self.bound.remove(0).1, true),
+        };
+        self.bound.push((character, index));
+        Some((self.spare[index].0 - XKB_KEYCODE_OFFSET, is_new))
+    }
+
+    /// The keymap text of xkbcommon with the bound characters added to its symbols
+    pub fn extend_keymap(&self, keymap: &str) -> String {
+        let symbols_start = keymap
+            .find("xkb_symbols")
+            .and_then(|start| Some(start + keymap[start..].find('{')? + 1));
+        let Some(symbols_start) = symbols_start.filter(|_| !self.is_empty()) else {
+            return keymap.to_string();
+        };
+        let mut symbols = String::new();
+        for &(character, index) in &self.bound {
+            let name = &self.spare[index].1;
+            let keysym = xkb::keysym_get_name(keysym(character));
+            symbols.push_str(&format!("\n\tkey <{name}> {{ [ {keysym} ] }};"));
+        }
+        let mut extended = keymap.to_string();
+        extended.insert_str(symbols_start, &symbols);
+        extended
+    }
+}
+
+#[test]
+fn tst_extra_keys() {
+    let keymap = crate::new_keymap("us", &crate::config::Config::default());
+    let mut extra_keys = ExtraKeys::new(&keymap);
+    let (smile, is_new) = extra_keys.bind('😀').unwrap();
+    assert!(is_new);
+    let (ya, _) = extra_keys.bind('я').unwrap();
+    assert_eq!(extra_keys.bind('😀'), Some((smile, false)));
+    assert_eq!(extra_keys.bind('\n'), None);
+
+    let text = extra_keys.extend_keymap(&keymap.get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1));
+    let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    let extended = xkb::Keymap::new_from_string(
+        &context,
+        text,
+        xkb::KEYMAP_FORMAT_TEXT_V1,
+        xkb::KEYMAP_COMPILE_NO_FLAGS,
+    )
+    .unwrap();
+    let state = xkb::State::new(&extended);
+    assert_eq!(state.key_get_utf8(smile + XKB_KEYCODE_OFFSET), "😀");
+    assert_eq!(state.key_get_utf8(ya + XKB_KEYCODE_OFFSET), "я");
+    assert_eq!(state.key_get_utf8(30 + XKB_KEYCODE_OFFSET), "a");
+
+    // NOTE: with two keycodes the third character takes the one of the least recently typed
+    extra_keys.spare.truncate(2);
+    assert_eq!(extra_keys.bind('€'), Some((ya, true)));
+    extra_keys.clear();
+    assert_eq!(extra_keys.bind('€'), Some((smile, true)));
+}
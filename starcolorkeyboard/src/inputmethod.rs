@@ -0,0 +1,165 @@
+use wayland_client::WEnum;
+
+use wayland_protocols::wp::text_input::zv3::client::zwp_text_input_v3::{
+    ContentHint, ContentPurpose,
+};
+
+use wayland_protocols_misc::zwp_input_method_v2::client::zwp_input_method_v2::{
+    self, ZwpInputMethodV2,
+};
+
+use xkbcommon::xkb;
+
+use crate::{consts::XKB_KEYCODE_OFFSET, keyboardlayouts::Purpose, KeyModifierType};
+
+/// The modifier combinations tried to type a character with the keymap, the plainest first
+const FALLBACK_MODIFIERS: [KeyModifierType; 4] = [
+    KeyModifierType::NoMod,
+    KeyModifierType::Shift,
+    KeyModifierType::AltGr,
+    KeyModifierType::Shift.union(KeyModifierType::AltGr),
+];
+
+/// The input method of the seat, text goes to the focused text input without any keycode
+pub struct InputMethod {
+    input_method: ZwpInputMethodV2,
+    pending_active: bool,
+    active: bool,
+    pending_content: (ContentHint, ContentPurpose),
+    content: (ContentHint, ContentPurpose),
+    /// count of the done events, every commit has to send it
+    serial: u32,
+}
+
+impl InputMethod {
+    pub fn new(input_method: ZwpInputMethodV2) -> Self {
+        InputMethod {
+            input_method,
+            pending_active: false,
+            active: false,
+            pending_content: (ContentHint::None, ContentPurpose::Normal),
+            content: (ContentHint::None, ContentPurpose::Normal),
+            serial: 0,
+        }
+    }
+
+    /// A text input has the focus, without it the compositor drops the text
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// The keys to show for the focused text input
+    pub fn purpose(&self) -> Purpose {
+        if !self.active {
+            return Purpose::Normal;
+        }
+        match self.content.1 {
+            ContentPurpose::Digits
+            | ContentPurpose::Number
+            | ContentPurpose::Phone
+            | ContentPurpose::Pin => Purpose::Digits,
+            ContentPurpose::Email => Purpose::Email,
+            ContentPurpose::Url => Purpose::Url,
+            _ => Purpose::Normal,
+        }
+    }
+
+    /// Passwords and the like, nothing typed there may be shown or remembered
+    pub fn is_sensitive(&self) -> bool {
+        let (hint, purpose) = self.content;
+        self.active
+            && (matches!(purpose, ContentPurpose::Password | ContentPurpose::Pin)
+                || hint.intersects(ContentHint::SensitiveData | ContentHint::HiddenText))
+    }
+
+    /// Type the text, it replaces the preedit text
+    pub fn commit_string(&self, text: &str) {
+        self.input_method.set_preedit_string(String::new(), 0, 0);
+        self.input_method.commit_string(text.to_string());
+        self.input_method.commit(self.serial);
+    }
+
+    /// Type the text over the bytes before the cursor
+    pub fn replace_before(&self, length: usize, text: &str) {
+        self.input_method.set_preedit_string(String::new(), 0, 0);
+        self.input_method.delete_surrounding_text(length as u32, 0);
+        self.input_method.commit_string(text.to_string());
+        self.input_method.commit(self.serial);
+    }
+
+    /// Show the text at the cursor without typing it, an empty text clears it
+    pub fn set_preedit(&self, text: &str) {
+        let cursor = text.len() as i32;
+        self.input_method
+            .set_preedit_string(text.to_string(), cursor, cursor);
+        self.input_method.commit(self.serial);
+    }
+
+    /// Returns false if the input method is unavailable and should be dropped
+    pub fn handle_event(&mut self, event: zwp_input_method_v2::Event) -> bool {
+        match event {
+            zwp_input_method_v2::Event::Activate => {
+                // NOTE: activate resets the content type of the last text input
+                self.pending_active = true;
+                self.pending_content = (ContentHint::None, ContentPurpose::Normal);
+            }
+            zwp_input_method_v2::Event::ContentType { hint, purpose } => {
+                let hint = match hint {
+                    WEnum::Value(hint) => hint,
+                    WEnum::Unknown(bits) => ContentHint::from_bits_truncate(bits),
+                };
+                let purpose = match purpose {
+                    WEnum::Value(purpose) => purpose,
+                    WEnum::Unknown(_) => ContentPurpose::Normal,
+                };
+                self.pending_content = (hint, purpose);
+            }
+            zwp_input_method_v2::Event::Deactivate => self.pending_active = false,
+            zwp_input_method_v2::Event::Done => {
+                self.serial += 1;
+                self.active = self.pending_active;
+                self.content = self.pending_content;
+            }
+            zwp_input_method_v2::Event::Unavailable => {
+                eprintln!("another input method is running, type with keycodes");
+                self.input_method.destroy();
+                return false;
+            }
+            _ => {}
+        }
+        true
+    }
+}
+
+/// The key and the modifiers which type the text with the keymap, the key is an evdev keycode
+pub fn find_keymap_key(keymap: &xkb::Keymap, text: &str) -> Option<(u32, KeyModifierType)> {
+    FALLBACK_MODIFIERS.into_iter().find_map(|modifiers| {
+        let mut state = xkb::State::new(keymap);
+        state.update_mask(modifiers.to_xkb_mask(keymap), 0, 0, 0, 0, 0);
+        (keymap.min_keycode()..=keymap.max_keycode())
+            .find(|&keycode| state.key_get_utf8(keycode) == text)
+            .map(|keycode| (keycode - XKB_KEYCODE_OFFSET, modifiers))
+    })
+}
+
+#[test]
+fn tst_find_keymap_key() {
+    let keymap = crate::new_keymap("de", &crate::config::Config::default());
+    assert_eq!(
+        find_keymap_key(&keymap, "a"),
+        Some((30, KeyModifierType::NoMod))
+    );
+    assert_eq!(
+        find_keymap_key(&keymap, "A"),
+        Some((30, KeyModifierType::Shift))
+    );
+    assert_eq!(
+        find_keymap_key(&keymap, "ä"),
+        Some((40, KeyModifierType::NoMod))
+    );
+    assert_eq!(
+        find_keymap_key(&keymap, "@"),
+        Some((16, KeyModifierType::AltGr))
+    );
+    assert_eq!(find_keymap_key(&keymap, "😀"), None);
+}
@@ -1,71 +1,814 @@
-const ENGLISH_LAYOUT: &str = include_str!("../asserts/layoutassert/us.json");
+mod legacy;
+
+const ENGLISH_LAYOUT: &str = include_str!("../asserts/mainkeylayout/enUS.json");
+const NORWEGIAN_LAYOUT: &str = include_str!("../asserts/mainkeylayout/noNO.json");
+const GERMAN_LAYOUT: &str = include_str!("../asserts/mainkeylayout/deDE.json");
+const FRENCH_AZERTY_LAYOUT: &str = include_str!("../asserts/mainkeylayout/frFR.json");
+const RUSSIAN_LAYOUT: &str = include_str!("../asserts/mainkeylayout/ruRU.json");
+const PURPOSE_LAYOUTS: &str = include_str!("../asserts/mainkeylayout/purposes.json");
+const PAGE_LAYOUTS: &str = include_str!("../asserts/mainkeylayout/pages.json");
+
+/// The page name of the main rows, page keys with it switch back to them
+pub const MAIN_PAGE: &str = "main";
+
+/// The version of the layout file format this build reads and writes
+pub const LAYOUT_VERSION: u32 = 2;
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+};
 
 use serde::{Deserialize, Serialize};
-use serde_json::Result;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct KeyBoardLayout {
+use xkbcommon::xkb;
+
+use crate::{config, consts::XKB_KEYCODE_OFFSET, otherkeys};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct KeyboardLayout {
+    pub version: u32,
     pub name: String,
-    pub layoutname: String,
-    pub keys: Vec<LayoutKey>,
+    /// the xkb layout the keycodes are typed with
+    pub layout: String,
+    pub rows: Vec<Vec<LayoutKey>>,
+    /// the square keys right of the main rows, `numbers[row]` is one row of the block
+    #[serde(default)]
+    pub numbers: Vec<Vec<LayoutKey>>,
+    /// keys for the text inputs of a purpose, a missing purpose uses the embedded ones
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub purposes: BTreeMap<Purpose, PurposeLayout>,
+    /// rows shown instead of the main rows by the page keys, without any the embedded ones
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pages: BTreeMap<String, Vec<Vec<LayoutKey>>>,
+    /// a block left of the number block for the arrows and the like, `[]` never adds it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub navigation: Option<Vec<Vec<LayoutKey>>>,
+}
+
+/// What the focused text input is for, as far as the keys change for it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Purpose {
+    #[default]
+    Normal,
+    Digits,
+    Email,
+    Url,
+}
+
+/// Changes of the layout for one purpose
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PurposeLayout {
+    /// replaces the main rows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<Vec<Vec<LayoutKey>>>,
+    /// replaces the number block, an empty block is not drawn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub numbers: Option<Vec<Vec<LayoutKey>>>,
+    /// rows added below the number block
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_numbers: Vec<Vec<LayoutKey>>,
+}
+
+fn embedded_purposes() -> BTreeMap<Purpose, PurposeLayout> {
+    serde_json::from_str(PURPOSE_LAYOUTS).expect("the embedded purpose layouts should be valid")
+}
+
+fn embedded_pages() -> BTreeMap<String, Vec<Vec<LayoutKey>>> {
+    serde_json::from_str(PAGE_LAYOUTS).expect("the embedded pages should be valid")
+}
+
+/// The arrows, Home/End, PgUp/PgDn, Esc and Delete in three rows of four keys
+pub fn navigation_cluster() -> Vec<Vec<LayoutKey>> {
+    let key = |text: &str, key: u32, width: usize, start_pos: usize| LayoutKey {
+        text: text.to_string(),
+        cap: None,
+        shift: None,
+        altgr: None,
+        width,
+        start_pos,
+        key,
+        commit: None,
+        alternates: vec![],
+        page: None,
+    };
+    vec![
+        vec![
+            key("Esc", otherkeys::ESC, 2, 0),
+            key("⇱", otherkeys::HOME, 2, 2),
+            key("↑", otherkeys::UP, 2, 4),
+            key("⇲", otherkeys::END, 2, 6),
+        ],
+        vec![
+            key("←", otherkeys::LEFT, 2, 0),
+            key("↓", otherkeys::DOWN, 2, 2),
+            key("→", otherkeys::RIGHT, 2, 4),
+            key("Del", otherkeys::DELETE, 2, 6),
+        ],
+        vec![
+            key("PgUp", otherkeys::PAGE_UP, 4, 0),
+            key("PgDn", otherkeys::PAGE_DOWN, 4, 4),
+        ],
+    ]
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Esc, F1 to F12 in groups of four, Insert and Delete, as wide as the main rows
+pub fn function_row() -> Vec<LayoutKey> {
+    let key = |text: String, key: u32, width: usize, start_pos: usize| LayoutKey {
+        text,
+        cap: None,
+        shift: None,
+        altgr: None,
+        width,
+        start_pos,
+        key,
+        commit: None,
+        alternates: vec![],
+        page: None,
+    };
+    let function_keys = (otherkeys::F1..otherkeys::F1 + 10).chain([otherkeys::F11, otherkeys::F12]);
+    let mut row = vec![key("Esc".to_string(), otherkeys::ESC, 2, 0)];
+    for (index, code) in function_keys.enumerate() {
+        // NOTE: half a key between the groups of four
+        let start_pos = 3 + index * 2 + index / 4;
+        row.push(key(format!("F{}", index + 1), code, 2, start_pos));
+    }
+    row.push(key("Ins".to_string(), otherkeys::INSERT, 2, 30));
+    row.push(key("Del".to_string(), otherkeys::DELETE, 3, 32));
+    row
+}
+
+/// One key of a row, `width` and `start_pos` are counted in half key steps
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LayoutKey {
-    pub mainkey: String,
-    pub y: usize,
-    pub index: usize,
-    fillend: Option<bool>,
-    modkey: Option<bool>,
-    width: Option<usize>,
-    pub menu: Option<String>,
-    pub extra: Option<String>,
-    pub caps: Option<String>,
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cap: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shift: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub altgr: Option<String>,
+    pub width: usize,
+    pub start_pos: usize,
+    /// the evdev keycode, text keys get their code when the layout is loaded
+    #[serde(default)]
+    pub key: u32,
+    /// typed as text instead of the keycode, for characters the keymap does not have
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// offered in a popup while the key is held, like the accented letters
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternates: Vec<String>,
+    /// switches to the named page instead of typing, [MAIN_PAGE] for the main rows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<String>,
+}
+
+/// The spacing character shown for a dead key, xkb gives no utf8 for them
+fn dead_key_label(keysym: xkb::Keysym) -> Option<&'static str> {
+    match keysym {
+        xkb::keysyms::KEY_dead_grave => Some("`"),
+        xkb::keysyms::KEY_dead_acute => Some("´"),
+        xkb::keysyms::KEY_dead_circumflex => Some("^"),
+        xkb::keysyms::KEY_dead_tilde => Some("~"),
+        xkb::keysyms::KEY_dead_diaeresis => Some("¨"),
+        xkb::keysyms::KEY_dead_cedilla => Some("¸"),
+        xkb::keysyms::KEY_dead_abovering => Some("°"),
+        _ => None,
+    }
+}
+
+/// What the key types in the state, None for the keys without a printable character
+fn keymap_label(state: &xkb::State, key: u32) -> Option<String> {
+    let keycode = key + XKB_KEYCODE_OFFSET;
+    let text = state.key_get_utf8(keycode);
+    if text.is_empty() {
+        return dead_key_label(state.key_get_one_sym(keycode)).map(str::to_string);
+    }
+    if text.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return None;
+    }
+    Some(text)
+}
+
+/// The mask of the named modifier, 0 if the keymap has no such modifier
+pub fn mod_mask(keymap: &xkb::Keymap, mod_name: &str) -> u32 {
+    let index = keymap.mod_get_index(mod_name);
+    if index == xkb::MOD_INVALID {
+        0
+    } else {
+        1 << index
+    }
+}
+
+/// The modifiers the level3 shift key of the keymap sets, Mod5 if no key shifts the level3
+pub fn level3_mask(keymap: &xkb::Keymap) -> u32 {
+    let mut state = xkb::State::new(keymap);
+    let level3_key = (keymap.min_keycode()..=keymap.max_keycode())
+        .find(|&keycode| state.key_get_one_sym(keycode) == xkb::keysyms::KEY_ISO_Level3_Shift);
+    if let Some(keycode) = level3_key {
+        state.update_key(keycode, xkb::KeyDirection::Down);
+        let mask = state.serialize_mods(xkb::STATE_MODS_DEPRESSED);
+        if mask != 0 {
+            return mask;
+        }
+    }
+    mod_mask(keymap, "Mod5")
 }
 
-#[allow(unused)]
-impl LayoutKey {
-    pub fn is_fillend(&self) -> bool {
-        self.fillend.unwrap_or(false)
+fn keymap_state(keymap: &xkb::Keymap, mask: u32) -> xkb::State {
+    let mut state = xkb::State::new(keymap);
+    state.update_mask(mask, 0, 0, 0, 0, 0);
+    state
+}
+
+impl KeyboardLayout {
+    /// The layout with the keys of the purpose, numbered and ready to draw.
+    /// A purpose with its own rows has no pages
+    pub fn for_purpose(&self, purpose: Purpose) -> KeyboardLayout {
+        let mut layout = KeyboardLayout {
+            purposes: BTreeMap::new(),
+            ..self.clone()
+        };
+        if layout.pages.is_empty() {
+            layout.pages = embedded_pages();
+        }
+        let changes = match self.purposes.get(&purpose) {
+            Some(changes) => Some(changes.clone()),
+            None => embedded_purposes().remove(&purpose),
+        };
+        if let Some(PurposeLayout {
+            rows,
+            numbers,
+            mut extra_numbers,
+        }) = changes
+        {
+            if let Some(rows) = rows {
+                layout.rows = rows;
+                layout.pages.clear();
+            }
+            if let Some(numbers) = numbers {
+                layout.numbers = numbers;
+            }
+            layout.numbers.append(&mut extra_numbers);
+        }
+        layout.number_text_keys();
+        layout
+    }
+
+    /// Give every text key its own code above [otherkeys::TEXT_KEY_START], and every page key
+    /// its own code above [otherkeys::PAGE_KEY_START]
+    pub fn number_text_keys(&mut self) {
+        let mut text_code = otherkeys::TEXT_KEY_START;
+        let mut page_code = otherkeys::PAGE_KEY_START;
+        let keys = self
+            .rows
+            .iter_mut()
+            .chain(self.numbers.iter_mut())
+            .chain(self.navigation.iter_mut().flatten())
+            .chain(self.pages.values_mut().flatten())
+            .flatten();
+        for key in keys {
+            if key.page.is_some() {
+                key.key = page_code;
+                page_code += 1;
+            } else if key.commit.is_some() {
+                key.key = text_code;
+                text_code += 1;
+            }
+        }
+    }
+
+    /// All the keys, the ones of the pages too
+    pub fn keys(&self) -> impl Iterator<Item = &LayoutKey> {
+        self.rows
+            .iter()
+            .chain(self.numbers.iter())
+            .chain(self.navigation.iter().flatten())
+            .chain(self.pages.values().flatten())
+            .flatten()
     }
 
-    pub fn is_modkey(&self) -> bool {
-        self.modkey.unwrap_or(false)
+    /// The text a text key types
+    pub fn commit_text(&self, code: u32) -> Option<&str> {
+        self.keys().find(|key| key.key == code)?.commit.as_deref()
+    }
+
+    /// The page a page key switches to
+    pub fn page_name(&self, code: u32) -> Option<&str> {
+        self.keys().find(|key| key.key == code)?.page.as_deref()
+    }
+
+    /// Replace the labels with what the keymap really types, keys xkb cannot name keep their text
+    pub fn apply_keymap_labels(&mut self, keymap: &xkb::Keymap) {
+        let normal = keymap_state(keymap, 0);
+        let cap = keymap_state(keymap, mod_mask(keymap, xkb::MOD_NAME_CAPS));
+        let shift = keymap_state(keymap, mod_mask(keymap, xkb::MOD_NAME_SHIFT));
+        let altgr = keymap_state(keymap, level3_mask(keymap));
+        let purpose_keys = self.purposes.values_mut().flat_map(|purpose| {
+            let rows = purpose.rows.iter_mut().flatten();
+            let numbers = purpose.numbers.iter_mut().flatten();
+            rows.chain(numbers).chain(purpose.extra_numbers.iter_mut())
+        });
+        let rows = self
+            .rows
+            .iter_mut()
+            .chain(self.numbers.iter_mut())
+            .chain(self.navigation.iter_mut().flatten());
+        let pages = self.pages.values_mut().flatten();
+        for key in rows.chain(pages).chain(purpose_keys).flatten() {
+            let Some(text) = keymap_label(&normal, key.key) else {
+                continue;
+            };
+            let other_label =
+                |state: &xkb::State| keymap_label(state, key.key).filter(|label| *label != text);
+            key.cap = other_label(&cap);
+            key.shift = other_label(&shift);
+            key.altgr = other_label(&altgr);
+            key.text = text;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LayoutError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, serde_json::Error),
+    Version(PathBuf, u32),
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::Io(path, err) => write!(f, "{}: {err}", path.display()),
+            LayoutError::Parse(path, err) => {
+                // NOTE: serde_json appends the position itself, we print it as file:line:column
+                let position = format!(" at line {} column {}", err.line(), err.column());
+                let message = err.to_string();
+                write!(
+                    f,
+                    "{}:{}:{}: {}",
+                    path.display(),
+                    err.line(),
+                    err.column(),
+                    message.strip_suffix(&position).unwrap_or(&message)
+                )
+            }
+            LayoutError::Version(path, version) => write!(
+                f,
+                "{}: layout version {version} is newer than the supported version {LAYOUT_VERSION}",
+                path.display()
+            ),
+        }
     }
+}
 
-    pub fn width(&self) -> usize {
-        self.width.unwrap_or(2)
+impl std::error::Error for LayoutError {}
+
+/// Parse a layout file, files in the old formats are converted to the current one
+pub fn parse_layout(content: &str, path: &Path) -> Result<KeyboardLayout, LayoutError> {
+    let to_error = |err| LayoutError::Parse(path.to_path_buf(), err);
+    let value: serde_json::Value = serde_json::from_str(content).map_err(to_error)?;
+    if value.is_array() {
+        let rows = serde_json::from_str(content).map_err(to_error)?;
+        return Ok(legacy::convert_main_layout(rows, path));
     }
+    match value.get("version").and_then(|version| version.as_u64()) {
+        Some(version) if version > LAYOUT_VERSION as u64 => {
+            Err(LayoutError::Version(path.to_path_buf(), version as u32))
+        }
+        Some(version) if version < LAYOUT_VERSION as u64 => {
+            let layout = serde_json::from_str(content).map_err(to_error)?;
+            Ok(legacy::upgrade_layout(layout))
+        }
+        Some(_) => serde_json::from_str(content).map_err(to_error),
+        None => {
+            let layout = serde_json::from_str(content).map_err(to_error)?;
+            Ok(legacy::convert_keyboard_layout(layout))
+        }
+    }
+}
+
+pub fn read_layout(path: &Path) -> Result<KeyboardLayout, LayoutError> {
+    let content =
+        std::fs::read_to_string(path).map_err(|err| LayoutError::Io(path.to_path_buf(), err))?;
+    parse_layout(&content, path)
 }
 
-#[allow(unused)]
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Layouts {
     #[default]
     EnglishUs,
-    //Norwegian,
+    Norwegian,
+    German,
+    FrenchAzerty,
+    Russian,
 }
 
-#[allow(unused)]
 impl Layouts {
-    pub fn to_layout_name(&self) -> &str {
+    /// Every embedded layout
+    pub const ALL: [Layouts; 5] = [
+        Self::EnglishUs,
+        Self::Norwegian,
+        Self::German,
+        Self::FrenchAzerty,
+        Self::Russian,
+    ];
+
+    pub fn from_layout_name(name: &str) -> Option<Self> {
+        match name {
+            "us" => Some(Self::EnglishUs),
+            "no" => Some(Self::Norwegian),
+            "de" => Some(Self::German),
+            "fr" => Some(Self::FrenchAzerty),
+            "ru" => Some(Self::Russian),
+            _ => None,
+        }
+    }
+
+    pub fn to_layout_name(self) -> &'static str {
         match self {
-            //Self::Norwegian => "no",
             Self::EnglishUs => "us",
+            Self::Norwegian => "no",
+            Self::German => "de",
+            Self::FrenchAzerty => "fr",
+            Self::Russian => "ru",
+        }
+    }
+
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|layout| layout.to_file_name() == name)
+    }
+
+    pub fn to_file_name(self) -> &'static str {
+        match self {
+            Self::EnglishUs => "enUS",
+            Self::Norwegian => "noNO",
+            Self::German => "deDE",
+            Self::FrenchAzerty => "frFR",
+            Self::Russian => "ruRU",
         }
     }
 
-    pub fn get_layout(&self) -> Result<KeyBoardLayout> {
+    fn embedded_layout(self) -> &'static str {
         match self {
-            Layouts::EnglishUs => Ok(serde_json::from_str(ENGLISH_LAYOUT)?),
+            Self::EnglishUs => ENGLISH_LAYOUT,
+            Self::Norwegian => NORWEGIAN_LAYOUT,
+            Self::German => GERMAN_LAYOUT,
+            Self::FrenchAzerty => FRENCH_AZERTY_LAYOUT,
+            Self::Russian => RUSSIAN_LAYOUT,
         }
     }
+
+    pub fn get_layout(self) -> serde_json::Result<KeyboardLayout> {
+        serde_json::from_str(self.embedded_layout())
+    }
+}
+
+/// The `*.json` files of the dir in the order of their names, none if it cannot be read
+fn layout_files(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json") && path.is_file())
+        .collect();
+    files.sort();
+    files
+}
+
+/// The first file in the dirs whose `layout` is the xkb layout `name`, the embedded layout
+/// without such a file, None if there is neither
+fn find_layout(dirs: &[PathBuf], name: &str) -> Option<KeyboardLayout> {
+    for path in dirs.iter().flat_map(|dir| layout_files(dir)) {
+        match read_layout(&path) {
+            Ok(layout) if layout.layout == name => return Some(layout),
+            Ok(_) => {}
+            Err(err) => eprintln!("{err}, skip it"),
+        }
+    }
+    let embedded = Layouts::from_layout_name(name)?;
+    Some(
+        embedded
+            .get_layout()
+            .expect("the embedded layout should be valid"),
+    )
+}
+
+/// Search the layout dirs for a file of the xkb layout `name`, fallback to the embedded one
+pub fn load_layout(name: &str) -> Option<KeyboardLayout> {
+    find_layout(&config::layout_dirs(), name)
+}
+
+/// The right alt key shifts the level3 in the keymap, it is AltGr then
+pub fn has_altgr(keymap: &xkb::Keymap) -> bool {
+    let state = xkb::State::new(keymap);
+    state.key_get_one_sym(otherkeys::ALT_RIGHT + XKB_KEYCODE_OFFSET)
+        == xkb::keysyms::KEY_ISO_Level3_Shift
 }
 
 #[test]
 fn tst_layout_read() {
-    let us_keyboard: KeyBoardLayout = serde_json::from_str(ENGLISH_LAYOUT).unwrap();
+    let us_keyboard = Layouts::EnglishUs.get_layout().unwrap();
+    assert_eq!(us_keyboard.version, LAYOUT_VERSION);
     assert_eq!(us_keyboard.name, "usbase".to_string());
-    assert_eq!(us_keyboard.layoutname, "us".to_string());
-    assert_eq!(us_keyboard.keys[0].mainkey, "`".to_string());
+    assert_eq!(us_keyboard.layout, "us".to_string());
+    assert_eq!(us_keyboard.rows[0][0].text, "Tab".to_string());
+    assert_eq!(us_keyboard.numbers.len(), 3);
+    assert_eq!(us_keyboard.numbers[0][0].text, "1".to_string());
+    assert_eq!(us_keyboard.numbers[0][0].shift, Some("!".to_string()));
+    assert_eq!(us_keyboard.rows[0][3].text, "e".to_string());
+    assert_eq!(us_keyboard.rows[0][3].alternates[0], "é".to_string());
+    assert_eq!(us_keyboard.numbers[0][0].key, 2);
+}
+
+#[test]
+fn tst_layout_read_norwegian() {
+    let no_keyboard = Layouts::Norwegian.get_layout().unwrap();
+    assert_eq!(no_keyboard.name, "nobase".to_string());
+    assert_eq!(no_keyboard.layout, Layouts::Norwegian.to_layout_name());
+    let aring = no_keyboard.rows[0]
+        .iter()
+        .find(|key| key.key == 26)
+        .unwrap();
+    assert_eq!(aring.text, "å".to_string());
+    assert_eq!(aring.cap, Some("Å".to_string()));
+    let e_key = no_keyboard.rows[0]
+        .iter()
+        .find(|key| key.key == 18)
+        .unwrap();
+    assert_eq!(e_key.altgr, Some("€".to_string()));
+}
+
+#[test]
+fn tst_layout_read_german() {
+    let de_keyboard = Layouts::German.get_layout().unwrap();
+    assert_eq!(de_keyboard.name, "debase".to_string());
+    assert_eq!(de_keyboard.layout, Layouts::German.to_layout_name());
+    let z_key = de_keyboard.rows[0]
+        .iter()
+        .find(|key| key.key == 21)
+        .unwrap();
+    assert_eq!(z_key.text, "z".to_string());
+    let q_key = de_keyboard.rows[0]
+        .iter()
+        .find(|key| key.key == 16)
+        .unwrap();
+    assert_eq!(q_key.altgr, Some("@".to_string()));
+    assert!(de_keyboard.rows[2].iter().any(|key| key.key == 86));
+}
+
+#[test]
+fn tst_layout_read_french() {
+    let fr_keyboard = Layouts::FrenchAzerty.get_layout().unwrap();
+    assert_eq!(fr_keyboard.name, "frbase".to_string());
+    assert_eq!(fr_keyboard.layout, Layouts::FrenchAzerty.to_layout_name());
+    assert_eq!(fr_keyboard.rows[0][1].text, "a".to_string());
+    let u_grave = fr_keyboard.rows[1]
+        .iter()
+        .find(|key| key.key == 40)
+        .unwrap();
+    assert_eq!(u_grave.text, "ù".to_string());
+    assert_eq!(u_grave.shift, Some("%".to_string()));
+    assert_eq!(fr_keyboard.numbers[0][0].text, "&".to_string());
+    assert_eq!(fr_keyboard.numbers[0][0].shift, Some("1".to_string()));
+}
+
+#[test]
+fn tst_layout_read_russian() {
+    let ru_keyboard = Layouts::Russian.get_layout().unwrap();
+    assert_eq!(ru_keyboard.name, "rubase".to_string());
+    assert_eq!(ru_keyboard.layout, Layouts::Russian.to_layout_name());
+    assert_eq!(ru_keyboard.rows[0][1].text, "й".to_string());
+    assert_eq!(ru_keyboard.rows[0][1].cap, Some("Й".to_string()));
+}
+
+#[test]
+fn tst_layout_version() {
+    let path = Path::new("future.json");
+    let err = parse_layout(
+        r#"{ "version": 99, "name": "", "layout": "", "rows": [] }"#,
+        path,
+    )
+    .unwrap_err();
+    assert!(matches!(err, LayoutError::Version(_, 99)));
+}
+
+#[test]
+fn tst_layout_parse_error() {
+    use std::io::Write;
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(b"{\n  \"version\": 1,\n  \"rows\": [[{ \"text\": \"q\" }]]\n}")
+        .unwrap();
+    let err = read_layout(file.path()).unwrap_err();
+    assert!(matches!(err, LayoutError::Parse(_, ref err) if err.line() == 3));
+    let message = err.to_string();
+    assert!(message.starts_with(&format!("{}:3:", file.path().display())));
+    assert!(!message.contains(" at line "));
+}
+
+#[test]
+fn tst_keymap_labels() {
+    let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    let keymap = xkb::Keymap::new_from_names(
+        &context,
+        "",
+        "",
+        "de",
+        "",
+        None,
+        xkb::KEYMAP_COMPILE_NO_FLAGS,
+    )
+    .unwrap();
+    // NOTE: the us layout, so every printable label is replaced by the german one
+    let mut layout = Layouts::EnglishUs.get_layout().unwrap();
+    layout.apply_keymap_labels(&keymap);
+    let find = |key: u32| {
+        layout
+            .rows
+            .iter()
+            .chain(layout.numbers.iter())
+            .flatten()
+            .find(|map| map.key == key)
+            .unwrap()
+    };
+    assert_eq!(find(21).text, "z".to_string());
+    assert_eq!(find(21).cap, Some("Z".to_string()));
+    assert_eq!(find(16).altgr, Some("@".to_string()));
+    assert_eq!(find(3).shift, Some("\"".to_string()));
+    assert_eq!(find(13).text, "´".to_string());
+    assert_eq!(find(15).text, "Tab".to_string());
+}
+
+#[test]
+fn tst_keymap_variant() {
+    let mut config = crate::config::Config {
+        variants: BTreeMap::from([("us".to_string(), "altgr-intl".to_string())]),
+        options: "lv3:ralt_switch".to_string(),
+        ..Default::default()
+    };
+    let keymap = crate::new_keymap("us", &config);
+    assert_eq!(level3_mask(&keymap), mod_mask(&keymap, "Mod5"));
+    let mut layout = Layouts::EnglishUs.get_layout().unwrap();
+    layout.apply_keymap_labels(&keymap);
+    let e_key = layout.rows[0].iter().find(|key| key.key == 18).unwrap();
+    assert_eq!(e_key.altgr, Some("é".to_string()));
+
+    // NOTE: a variant xkb does not know falls back to the layout alone
+    config
+        .variants
+        .insert("us".to_string(), "nonexistent".to_string());
+    let keymap = crate::new_keymap("us", &config);
+    assert_eq!(keymap.layout_get_name(0), "English (US)");
+}
+
+#[test]
+fn tst_text_keys() {
+    let mut layout: KeyboardLayout = serde_json::from_str(
+        r#"{
+            "version": 2,
+            "name": "text",
+            "layout": "us",
+            "rows": [[
+                { "text": "a", "width": 2, "start_pos": 0, "key": 30 },
+                { "text": "€", "width": 2, "start_pos": 2, "commit": "€" },
+                { "text": "😀", "width": 2, "start_pos": 4, "commit": "😀" }
+            ]]
+        }"#,
+    )
+    .unwrap();
+    layout.number_text_keys();
+    assert_eq!(layout.rows[0][0].key, 30);
+    assert_eq!(layout.rows[0][1].key, otherkeys::TEXT_KEY_START);
+    assert_eq!(
+        layout.commit_text(otherkeys::TEXT_KEY_START + 1),
+        Some("😀")
+    );
+    assert_eq!(layout.commit_text(30), None);
+}
+
+#[test]
+fn tst_layout_purposes() {
+    let layout = Layouts::EnglishUs.get_layout().unwrap();
+    let digits = layout.for_purpose(Purpose::Digits);
+    assert!(digits.numbers.is_empty());
+    assert_eq!(digits.rows[0][0].commit, Some("1".to_string()));
+    assert_eq!(digits.commit_text(digits.rows[0][0].key), Some("1"));
+    let email = layout.for_purpose(Purpose::Email);
+    assert_eq!(email.rows.len(), layout.rows.len());
+    assert_eq!(email.numbers.len(), layout.numbers.len() + 1);
+    assert_eq!(email.numbers[3][0].text, "@".to_string());
+    let url = layout.for_purpose(Purpose::Url);
+    assert_eq!(url.numbers[3][0].text, "/".to_string());
+    assert_eq!(url.numbers[3][1].text, ".com".to_string());
+    assert_eq!(layout.for_purpose(Purpose::Normal).numbers.len(), 3);
+}
+
+#[test]
+fn tst_layout_pages() {
+    let layout = Layouts::EnglishUs
+        .get_layout()
+        .unwrap()
+        .for_purpose(Purpose::Normal);
+    let page_key = layout.rows[3]
+        .iter()
+        .find(|key| key.page.is_some())
+        .unwrap();
+    assert!(otherkeys::is_page_key(page_key.key));
+    assert_eq!(layout.page_name(page_key.key), Some("symbols"));
+    let symbols = &layout.pages["symbols"];
+    assert_eq!(symbols[0][1].text, "!".to_string());
+    assert_eq!(layout.commit_text(symbols[0][1].key), Some("!"));
+    let back = symbols[3].iter().find(|key| key.page.is_some()).unwrap();
+    assert_eq!(layout.page_name(back.key), Some(MAIN_PAGE));
+    assert!(layout.for_purpose(Purpose::Digits).pages.is_empty());
+}
+
+#[test]
+fn tst_embedded_main_layouts() {
+    for layout in Layouts::ALL {
+        let keyboard = layout.get_layout().unwrap();
+        assert_eq!(keyboard.rows.len(), 4);
+        assert_eq!(keyboard.layout, layout.to_layout_name());
+        // NOTE: the menu key is Super, the page key must not take its place
+        assert!(keyboard
+            .rows
+            .iter()
+            .flatten()
+            .any(|key| key.key == otherkeys::MENU));
+    }
+}
+
+#[test]
+fn tst_find_layout() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut czech = Layouts::EnglishUs.get_layout().unwrap();
+    czech.name = "czech".to_string();
+    czech.layout = "cz".to_string();
+    let content = serde_json::to_string(&czech).unwrap();
+    std::fs::write(dir.path().join("czech.json"), content).unwrap();
+    std::fs::write(dir.path().join("notes.txt"), "not a layout").unwrap();
+    let dirs = [dir.path().to_path_buf()];
+    // NOTE: a new layout needs only its file, the name of the file does not matter
+    assert_eq!(find_layout(&dirs, "cz").unwrap().name, "czech");
+    assert_eq!(find_layout(&dirs, "de").unwrap().layout, "de");
+    assert!(find_layout(&dirs, "xx").is_none());
+    czech.layout = "de".to_string();
+    let content = serde_json::to_string(&czech).unwrap();
+    std::fs::write(dir.path().join("mine.json"), content).unwrap();
+    assert_eq!(find_layout(&dirs, "de").unwrap().name, "czech");
+}
+
+#[test]
+fn tst_has_altgr() {
+    let config = config::Config::default();
+    for layout in Layouts::ALL {
+        let keymap = crate::new_keymap(layout.to_layout_name(), &config);
+        let altgr = matches!(
+            layout,
+            Layouts::Norwegian | Layouts::German | Layouts::FrenchAzerty
+        );
+        assert_eq!(has_altgr(&keymap), altgr, "{layout:?}");
+    }
+}
+
+#[test]
+fn tst_layout_navigation() {
+    let mut layout = Layouts::EnglishUs.get_layout().unwrap();
+    assert_eq!(layout.for_purpose(Purpose::Normal).numbers.len(), 3);
+    layout.navigation = Some(navigation_cluster());
+    let normal = layout.for_purpose(Purpose::Normal);
+    assert_eq!(normal.numbers.len(), 3);
+    assert_eq!(normal.navigation.as_ref().unwrap()[0][2].key, otherkeys::UP);
+    assert!(normal.keys().any(|key| key.key == otherkeys::UP));
+    // NOTE: the digits pad changes the number block, the navigation keys stay
+    let digits = layout.for_purpose(Purpose::Digits);
+    assert_eq!(
+        digits.navigation.as_ref().unwrap()[1][0].key,
+        otherkeys::LEFT
+    );
+}
+
+#[test]
+fn tst_function_row() {
+    let row = function_row();
+    let layout = Layouts::EnglishUs.get_layout().unwrap();
+    let width = |row: &[LayoutKey]| row.iter().map(|key| key.start_pos + key.width).max();
+    assert_eq!(width(&row), width(&layout.rows[0]));
+    let f_key = |number: usize| {
+        row.iter()
+            .find(|key| key.text == format!("F{number}"))
+            .unwrap()
+    };
+    assert_eq!(f_key(1).key, otherkeys::F1);
+    assert_eq!(f_key(10).key, otherkeys::F1 + 9);
+    assert_eq!(f_key(12).key, otherkeys::F12);
+    assert!(row
+        .windows(2)
+        .all(|pair| pair[0].start_pos + pair[0].width <= pair[1].start_pos));
 }
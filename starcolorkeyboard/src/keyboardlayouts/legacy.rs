@@ -0,0 +1,214 @@
+//! The layout files of the older formats, only read to convert them to the current one
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Deserialize;
+
+use super::{KeyboardLayout, LayoutKey, Layouts, LAYOUT_VERSION};
+
+/// The row width of the old keyboard layout, keys with `fillend` stretch to it
+const LEGACY_ROW_WIDTH: usize = 30;
+
+/// The evdev keycodes of a standard board, row by row, the old keyboard layout had no keycodes
+const LEGACY_ROW_KEYCODES: [&[u32]; 5] = [
+    &[41, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14],
+    &[15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 43],
+    &[58, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 28],
+    &[42, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54],
+    &[29, 125, 56, 57, 100, 126, 127, 97],
+];
+
+/// The key between left shift and z on iso boards
+const LEGACY_ISO_KEY: u32 = 86;
+
+/// One key of the old `mainkeylayout` files, a bare array of rows
+#[derive(Deserialize, Debug)]
+pub(super) struct MainLayout {
+    text: String,
+    cap: Option<String>,
+    shift: Option<String>,
+    altgr: Option<String>,
+    width: usize,
+    #[allow(unused)]
+    line: usize,
+    start_pos: usize,
+    key: u32,
+}
+
+/// The old `layoutassert` files, they were never drawn and have no keycodes
+#[derive(Deserialize, Debug)]
+pub(super) struct KeyBoardLayout {
+    name: String,
+    layoutname: String,
+    keys: Vec<OldLayoutKey>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OldLayoutKey {
+    mainkey: String,
+    y: usize,
+    fillend: Option<bool>,
+    width: Option<usize>,
+    extra: Option<String>,
+    caps: Option<String>,
+    altgr: Option<String>,
+}
+
+/// Version 1 has no number block, it was always drawn with the us one
+pub(super) fn upgrade_layout(mut layout: KeyboardLayout) -> KeyboardLayout {
+    if layout.version < 2 && layout.numbers.is_empty() {
+        layout.numbers = Layouts::EnglishUs
+            .get_layout()
+            .expect("the embedded layout should be valid")
+            .numbers;
+    }
+    layout.version = LAYOUT_VERSION;
+    layout
+}
+
+pub(super) fn convert_main_layout(rows: Vec<Vec<MainLayout>>, path: &Path) -> KeyboardLayout {
+    let file_name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let layout = Layouts::from_file_name(&file_name).unwrap_or_else(|| {
+        eprintln!(
+            "{}: no embedded layout is named {file_name}, type it with us",
+            path.display()
+        );
+        Layouts::default()
+    });
+    upgrade_layout(KeyboardLayout {
+        version: 1,
+        name: file_name,
+        layout: layout.to_layout_name().to_string(),
+        rows: rows
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|key| LayoutKey {
+                        text: key.text,
+                        cap: key.cap,
+                        shift: key.shift,
+                        altgr: key.altgr,
+                        width: key.width,
+                        start_pos: key.start_pos,
+                        key: key.key,
+                        commit: None,
+                        alternates: vec![],
+                        page: None,
+                    })
+                    .collect()
+            })
+            .collect(),
+        numbers: vec![],
+        purposes: BTreeMap::new(),
+        pages: BTreeMap::new(),
+        navigation: None,
+    })
+}
+
+pub(super) fn convert_keyboard_layout(layout: KeyBoardLayout) -> KeyboardLayout {
+    let mut rows: Vec<Vec<&OldLayoutKey>> = vec![];
+    for key in layout.keys.iter() {
+        if rows.len() <= key.y {
+            rows.resize_with(key.y + 1, Vec::new);
+        }
+        rows[key.y].push(key);
+    }
+    let rows = rows
+        .into_iter()
+        .enumerate()
+        .map(|(line, row)| {
+            let mut keycodes = LEGACY_ROW_KEYCODES
+                .get(line)
+                .map(|keycodes| keycodes.to_vec())
+                .unwrap_or_default();
+            if line == 3 && row.len() == keycodes.len() + 1 {
+                keycodes.insert(1, LEGACY_ISO_KEY);
+            }
+            let mut start_pos = 0;
+            row.into_iter()
+                .zip(keycodes)
+                .map(|(key, keycode)| {
+                    let mut width = key.width.unwrap_or(2);
+                    if key.fillend.unwrap_or(false) && start_pos < LEGACY_ROW_WIDTH {
+                        width = LEGACY_ROW_WIDTH - start_pos;
+                    }
+                    let converted = LayoutKey {
+                        text: key.mainkey.clone(),
+                        cap: key.caps.clone(),
+                        shift: key.extra.clone(),
+                        altgr: key.altgr.clone(),
+                        width,
+                        start_pos,
+                        key: keycode,
+                        commit: None,
+                        alternates: vec![],
+                        page: None,
+                    };
+                    start_pos += width;
+                    converted
+                })
+                .collect()
+        })
+        .collect();
+    upgrade_layout(KeyboardLayout {
+        version: 1,
+        name: layout.name,
+        layout: layout.layoutname,
+        rows,
+        numbers: vec![],
+        purposes: BTreeMap::new(),
+        pages: BTreeMap::new(),
+        navigation: None,
+    })
+}
+
+#[test]
+fn tst_convert_keyboard_layout() {
+    let content = r#"{
+        "name": "usbase",
+        "layoutname": "us",
+        "keys": [
+            { "mainkey": "`", "y": 0, "menu": "esc", "index": 1 },
+            { "mainkey": "1", "extra": "!", "y": 0, "index": 2 },
+            { "mainkey": "2", "extra": "@", "y": 0, "fillend": true, "index": 3 },
+            { "mainkey": "tab", "y": 1, "width": 3, "index": 4 },
+            { "mainkey": "q", "y": 1, "caps": "Q", "index": 5 }
+        ]
+    }"#;
+    let layout = super::parse_layout(content, Path::new("us.json")).unwrap();
+    assert_eq!(layout.version, LAYOUT_VERSION);
+    assert_eq!(layout.layout, "us".to_string());
+    assert_eq!(layout.rows.len(), 2);
+    assert_eq!(layout.rows[0][1].key, 2);
+    assert_eq!(layout.rows[0][1].shift, Some("!".to_string()));
+    assert_eq!(layout.rows[0][2].key, 3);
+    assert_eq!(layout.rows[0][2].width, LEGACY_ROW_WIDTH - 4);
+    assert_eq!(layout.rows[1][1].start_pos, 3);
+    assert_eq!(layout.rows[1][1].key, 16);
+    assert_eq!(layout.rows[1][1].cap, Some("Q".to_string()));
+}
+
+#[test]
+fn tst_convert_main_layout() {
+    let content = r#"[[
+        { "text": "q", "cap": "Q", "width": 2, "line": 0, "start_pos": 4, "key": 16 }
+    ]]"#;
+    let layout = super::parse_layout(content, Path::new("deDE.json")).unwrap();
+    assert_eq!(layout.name, "deDE".to_string());
+    assert_eq!(layout.layout, "de".to_string());
+    assert_eq!(layout.rows[0][0].start_pos, 4);
+    assert_eq!(layout.rows[0][0].key, 16);
+    assert_eq!(layout.numbers.len(), 3);
+}
+
+#[test]
+fn tst_upgrade_layout() {
+    let content = r#"{ "version": 1, "name": "mine", "layout": "us", "rows": [] }"#;
+    let layout = super::parse_layout(content, Path::new("mine.json")).unwrap();
+    assert_eq!(layout.version, LAYOUT_VERSION);
+    assert_eq!(layout.numbers[1][3].text, "0".to_string());
+    assert_eq!(layout.numbers[1][3].key, 11);
+}
@@ -1,21 +1,43 @@
+mod config;
 mod consts;
 mod dispatch;
+mod emoji;
+mod extrakeys;
+mod inputmethod;
 mod keyboardlayouts;
-#[allow(unused)]
 mod otherkeys;
 mod pangoui;
-use std::{ffi::CString, fs::File, io::Write, os::unix::prelude::AsRawFd, path::PathBuf};
+mod prediction;
+mod shm;
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::File,
+    io::Write,
+    os::unix::prelude::AsRawFd,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use calloop::{
+    timer::{TimeoutAction, Timer},
+    EventLoop, LoopHandle, RegistrationToken,
+};
 
+use config::Config;
 use consts::EXCULDE_ZONE_TOP;
-use keyboardlayouts::Layouts;
+use extrakeys::ExtraKeys;
+use inputmethod::InputMethod;
+use keyboardlayouts::{has_altgr, level3_mask, mod_mask, Layouts, Purpose};
+use shm::ShmPool;
 
 use wayland_client::{
     protocol::{
-        wl_buffer,
+        wl_buffer, wl_callback,
         wl_keyboard::{self, KeyState},
         wl_output, wl_seat, wl_shm, wl_surface,
     },
-    Connection, QueueHandle,
+    Connection, QueueHandle, WaylandSource,
 };
 
 use wayland_protocols_wlr::layer_shell::v1::client::{
@@ -27,19 +49,23 @@ use wayland_protocols::xdg::shell::client::xdg_wm_base;
 
 use wayland_protocols::xdg::xdg_output::zv1::client::{zxdg_output_manager_v1, zxdg_output_v1};
 
+use wayland_protocols_misc::zwp_input_method_v2::client::zwp_input_method_manager_v2;
+
 use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::{
     zwp_virtual_keyboard_manager_v1, zwp_virtual_keyboard_v1,
 };
 
 use xkbcommon::xkb;
 
-use pangoui::PangoUi;
+use pangoui::{Area, PangoUi};
+
+use prediction::{Dictionary, Predictor, UserDictionary};
 
 use bitflags::bitflags;
 
 bitflags! {
     #[allow(unused)]
-    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
     struct KeyModifierType : u32 {
         const NoMod = 0;
         const Shift = 1;
@@ -71,7 +97,64 @@ impl From<usize> for KeyModifierType {
     }
 }
 
+impl KeyModifierType {
+    /// Like [From<u32>], but the right alt is AltGr if the keymap has it
+    fn from_key(key: u32, altgr: bool) -> Self {
+        if key == otherkeys::ALT_RIGHT && altgr {
+            KeyModifierType::AltGr
+        } else {
+            key.into()
+        }
+    }
+
+    /// Name of the xkb modifier behind the flag
+    fn xkb_mod_name(self) -> Option<&'static str> {
+        Some(match self {
+            KeyModifierType::Shift => xkb::MOD_NAME_SHIFT,
+            KeyModifierType::CapsLock => xkb::MOD_NAME_CAPS,
+            KeyModifierType::Ctrl => xkb::MOD_NAME_CTRL,
+            KeyModifierType::Alt => xkb::MOD_NAME_ALT,
+            KeyModifierType::Super => xkb::MOD_NAME_LOGO,
+            _ => return None,
+        })
+    }
+
+    /// The modifier mask of the keymap, the bits follow the xkb modifier indices.
+    /// AltGr is the modifier the level3 shift key of the keymap sets
+    fn to_xkb_mask(self, keymap: &xkb::Keymap) -> u32 {
+        self.iter()
+            .map(|flag| match flag {
+                KeyModifierType::AltGr => level3_mask(keymap),
+                _ => flag.xkb_mod_name().map_or(0, |name| mod_mask(keymap, name)),
+            })
+            .fold(0, |mask, flag_mask| mask | flag_mask)
+    }
+}
+
+/// `--convert-layout <file>` prints the layout file converted to the current format
+fn convert_layout(path: Option<String>) {
+    let Some(path) = path else {
+        eprintln!("usage: starcolorkeyboard --convert-layout <file>");
+        std::process::exit(1);
+    };
+    match keyboardlayouts::read_layout(std::path::Path::new(&path)) {
+        Ok(layout) => println!("{}", serde_json::to_string_pretty(&layout).unwrap()),
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
+    let mut args = std::env::args().skip(1);
+    if let Some(arg) = args.next() {
+        if arg == "--convert-layout" {
+            convert_layout(args.next());
+            return;
+        }
+    }
+
     let conn = Connection::connect_to_env().unwrap();
 
     let mut event_queue = conn.new_event_queue();
@@ -80,7 +163,7 @@ fn main() {
     let display = conn.display();
     display.get_registry(&qhandle, ());
 
-    let mut state = State::init();
+    let mut state = State::init(Config::load());
 
     event_queue.blocking_dispatch(&mut state).unwrap();
     let mut displays: usize = 0;
@@ -100,8 +183,11 @@ fn main() {
 
     if state.layer_shell.is_some() && state.wm_base.is_some() {
         state.init_virtual_keyboard(&qhandle);
+        state.init_input_method(&qhandle);
         state.pangoui.set_size(state.get_size_from_display(0));
-        state.set_buffer(&qhandle, KeyModifierType::NoMod);
+        state.set_buffer(&qhandle);
+        // NOTE: with auto show, the keyboard waits for a text input in the title strip
+        state.is_min = state.config.auto_show && state.input_method.is_some();
         state.init_layer_surface(
             &qhandle,
             state.get_size_from_display(0),
@@ -109,9 +195,26 @@ fn main() {
         );
     }
 
+    let mut event_loop: EventLoop<State> =
+        EventLoop::try_new().expect("Failed to initialize the event loop");
+    WaylandSource::new(event_queue)
+        .unwrap()
+        .insert(event_loop.handle())
+        .unwrap();
+    state.loop_handle = Some(event_loop.handle());
+
     while state.running {
-        event_queue.blocking_dispatch(&mut state).unwrap();
+        event_loop.dispatch(None, &mut state).unwrap();
+        // NOTE: the timers send requests outside of the wayland source, flush them too
+        conn.flush().unwrap();
     }
+    state.predictor.user.save();
+}
+
+/// A finger on the surface and the key it holds, unique keys are held until the finger is up
+struct TouchPoint {
+    position: (f64, f64),
+    key: Option<u32>,
 }
 
 struct State {
@@ -123,34 +226,140 @@ struct State {
     base_surface: Option<wl_surface::WlSurface>,
     layer_shell: Option<zwlr_layer_shell_v1::ZwlrLayerShellV1>,
     layer_surface: Option<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1>,
+    buffers: Option<ShmPool>,
+    /// the buffer drawn last
     buffer: Option<wl_buffer::WlBuffer>,
+    /// damage waits to be drawn
+    dirty: bool,
+    /// the frame callback of the last commit, the next one waits for it
+    frame: Option<wl_callback::WlCallback>,
     wm_base: Option<xdg_wm_base::XdgWmBase>,
     xdg_output_manager: Option<zxdg_output_manager_v1::ZxdgOutputManagerV1>,
     zxdg_output: Vec<zxdg_output_v1::ZxdgOutputV1>,
     virtual_keyboard_manager: Option<zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1>,
     virtual_keyboard: Option<zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1>,
+    input_method_manager: Option<zwp_input_method_manager_v2::ZwpInputMethodManagerV2>,
+    /// None if the compositor has no input method, the text is typed with keycodes then
+    input_method: Option<InputMethod>,
     xkb_state: xkb::State,
+    /// the right alt key is AltGr in the keymap
+    altgr: bool,
+    /// the characters added to the uploaded keymap, the base keymap cannot type them
+    extra_keys: ExtraKeys,
+    /// uploads the base keymap again once nothing was typed with the extra keys for a while
+    keymap_restore: Option<RegistrationToken>,
+    /// writes the user dictionary once nothing was learned for a while
+    dictionary_save: Option<RegistrationToken>,
+    /// the xkb layouts, each with a layout file or an embedded layout
+    layouts: Vec<String>,
+    layout_index: usize,
+    config: Config,
     pangoui: PangoUi,
-    keymode: KeyModifierType,
+    /// modifiers held down right now
+    depressed: KeyModifierType,
+    /// modifiers tapped once, they act on the next key only
+    latched: KeyModifierType,
+    /// when the last modifier latched, a second tap within the repeat delay locks it
+    latched_at: Option<Instant>,
+    /// modifiers tapped twice and caps lock, they act until tapped again
+    locked: KeyModifierType,
+    /// a key was typed while a modifier was held, the modifier does not latch on release
+    modifier_used: bool,
+    predictor: Predictor,
+    /// the word left of the cursor as far as it was typed here, the suggestions complete it
+    word: String,
+    recent_emoji: emoji::Recent,
     position: (f64, f64),
-    touch_pos: (f64, f64),
+    touch_points: HashMap<i32, TouchPoint>,
     is_min: bool,
+    pressed_key: Option<u32>,
+    loop_handle: Option<LoopHandle<'static, State>>,
+    repeat_token: Option<(u32, RegistrationToken)>,
+    /// the held suggestion, without the timer once the long press forgot it
+    long_press: Option<(u32, Option<RegistrationToken>)>,
 }
 
-impl State {
-    fn init() -> Self {
-        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
-
-        let keymap = xkb::Keymap::new_from_names(
+/// The keymap of the layout with the variant and the options of the config, the layout alone
+/// if xkb cannot compile them and us if xkb has no such layout
+pub(crate) fn new_keymap(name: &str, config: &Config) -> xkb::Keymap {
+    let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    let compile = |name: &str, variant: &str, options: Option<String>| {
+        xkb::Keymap::new_from_names(
             &context,
             "",
             "",
-            Layouts::EnglishUs.to_layout_name(), // if no , it is norwegian
-            "",
-            None,
+            name,
+            variant,
+            options,
             xkb::KEYMAP_COMPILE_NO_FLAGS,
         )
-        .expect("xkbcommon keymap panicked!");
+    };
+    let variant = config.variants.get(name).map_or("", String::as_str);
+    let options = (!config.options.is_empty()).then(|| config.options.clone());
+    compile(name, variant, options)
+        .or_else(|| {
+            eprintln!(
+                "xkb cannot compile {name} with the variant {variant:?} and the options {:?}, \
+                 use the layout alone",
+                config.options
+            );
+            compile(name, "", None)
+        })
+        .or_else(|| {
+            eprintln!("xkb has no layout {name}, use us");
+            compile("us", "", None)
+        })
+        .expect("xkbcommon keymap panicked!")
+}
+
+/// Show the layout of the xkb layout `name` and return its keymap, the labels come from the
+/// keymap if the config wants them
+fn show_layout(pangoui: &mut PangoUi, name: &str, config: &Config) -> xkb::Keymap {
+    let layout = keyboardlayouts::load_layout(name).unwrap_or_else(|| {
+        eprintln!("the layout {name} is gone, use us");
+        Layouts::default()
+            .get_layout()
+            .expect("the embedded layout should be valid")
+    });
+    let keymap = new_keymap(&layout.layout, config);
+    pangoui.set_layout(layout, has_altgr(&keymap));
+    if config.keymap_labels {
+        pangoui.apply_keymap(&keymap);
+    }
+    keymap
+}
+
+impl State {
+    fn init(config: Config) -> Self {
+        let mut layouts: Vec<String> = config
+            .layouts
+            .iter()
+            .filter(|name| {
+                let known = keyboardlayouts::load_layout(name).is_some();
+                if !known {
+                    eprintln!("no layout file or embedded layout for {name}, skip it");
+                }
+                known
+            })
+            .cloned()
+            .collect();
+        if layouts.is_empty() {
+            layouts.push(Layouts::default().to_layout_name().to_string());
+        }
+        let predictor = if config.prediction {
+            Predictor {
+                dictionary: Dictionary::load(&layouts[0]),
+                user: UserDictionary::load(),
+            }
+        } else {
+            Predictor::default()
+        };
+        let recent_emoji = emoji::Recent::load();
+        let mut pangoui = PangoUi::default();
+        pangoui.set_navigation(config.navigation);
+        let keymap = show_layout(&mut pangoui, &layouts[0], &config);
+        pangoui.set_emoji_categories(emoji::categories());
+        pangoui.set_recent_emoji(recent_emoji.symbols.clone());
         State {
             running: true,
             wl_output: vec![],
@@ -160,37 +369,84 @@ impl State {
             base_surface: None,
             layer_shell: None,
             layer_surface: None,
+            buffers: None,
             buffer: None,
+            dirty: false,
+            frame: None,
             wm_base: None,
             xdg_output_manager: None,
             zxdg_output: vec![],
             virtual_keyboard_manager: None,
             virtual_keyboard: None,
+            input_method_manager: None,
+            input_method: None,
             xkb_state: xkb::State::new(&keymap),
-            pangoui: PangoUi::default(),
-            keymode: KeyModifierType::NoMod,
+            altgr: has_altgr(&keymap),
+            extra_keys: ExtraKeys::new(&keymap),
+            keymap_restore: None,
+            dictionary_save: None,
+            layouts,
+            layout_index: 0,
+            config,
+            pangoui,
+            depressed: KeyModifierType::NoMod,
+            latched: KeyModifierType::NoMod,
+            latched_at: None,
+            locked: KeyModifierType::NoMod,
+            modifier_used: false,
+            predictor,
+            word: String::new(),
+            recent_emoji,
             position: (0.0, 0.0),
-            touch_pos: (0.0, 0.0),
+            touch_points: HashMap::new(),
             is_min: false,
+            pressed_key: None,
+            loop_handle: None,
+            repeat_token: None,
+            long_press: None,
         }
     }
 
-    fn set_buffer(&mut self, qh: &QueueHandle<Self>, key_type: KeyModifierType) {
-        let (width, height) = self.pangoui.get_size();
-        let file = tempfile::tempfile().unwrap();
-        self.draw(key_type, &file);
-        let shm = self.wl_shm.as_ref().unwrap();
-        let pool = shm.create_pool(file.as_raw_fd(), width * height * 4, qh, ());
-        let buffer = pool.create_buffer(
-            0,
-            width,
-            height,
-            width * 4,
-            wl_shm::Format::Argb8888,
-            qh,
-            (),
-        );
-        self.buffer = Some(buffer);
+    /// Draw the next frame into a free buffer, the buffers of a new size are drawn whole.
+    /// The damage of the frame, None if the compositor reads all the buffers
+    fn set_buffer(&mut self, qh: &QueueHandle<Self>) -> Option<Vec<Area>> {
+        let size = self.pangoui.get_size();
+        if self.buffers.as_ref().map(ShmPool::size) != Some(size) {
+            if let Some(buffers) = self.buffers.take() {
+                buffers.destroy();
+            }
+            self.buffers = Some(ShmPool::new(self.wl_shm.as_ref().unwrap(), size, qh));
+        }
+        let (key_type, locked) = (self.active_modifiers(), self.locked);
+        let buffer = self
+            .buffers
+            .as_mut()
+            .unwrap()
+            .next_buffer(self.pangoui.pending_damage())?;
+        let damage = buffer.take_damage();
+        self.pangoui.ui(&buffer.surface, &damage, key_type, locked);
+        self.buffer = Some(buffer.buffer.clone());
+        Some(self.pangoui.take_damage())
+    }
+
+    /// The compositor is done with the buffer, it draws the damage waiting for a free buffer
+    fn release_buffer(&mut self, buffer: &wl_buffer::WlBuffer, qh: &QueueHandle<Self>) {
+        let released = self
+            .buffers
+            .as_mut()
+            .is_some_and(|buffers| buffers.release(buffer));
+        if released {
+            self.redraw(qh);
+        }
+    }
+
+    /// The compositor shows the last frame, the damage since then makes the next one
+    fn frame_done(&mut self, callback: &wl_callback::WlCallback, qh: &QueueHandle<Self>) {
+        if self.frame.as_ref() != Some(callback) {
+            return;
+        }
+        self.frame = None;
+        self.redraw(qh);
     }
 
     fn min_keyboard(&self) {
@@ -207,6 +463,25 @@ impl State {
         self.base_surface.as_ref().unwrap().commit();
     }
 
+    /// Show the keys for the purpose of the focused text input
+    fn set_purpose(&mut self, purpose: Purpose, qh: &QueueHandle<Self>) {
+        if self.pangoui.set_purpose(purpose) {
+            self.update_map(qh);
+        }
+    }
+
+    /// Expand the keyboard when a text input gains the focus and collapse it when the focus leaves
+    fn follow_text_input(&mut self, active: bool, qh: &QueueHandle<Self>) {
+        // NOTE: another text input, the word typed before is not at its cursor
+        self.word.clear();
+        self.update_suggestions(qh);
+        if !self.config.auto_show || self.is_min != active {
+            return;
+        }
+        self.is_min = !active;
+        self.min_keyboard();
+    }
+
     fn get_size_from_display(&self, index: usize) -> (i32, i32) {
         (self.wl_size[index].0, 300)
     }
@@ -217,6 +492,11 @@ impl State {
         (_width, height): (i32, i32),
         output: Option<&wl_output::WlOutput>,
     ) {
+        let height = if self.is_min {
+            EXCULDE_ZONE_TOP as i32
+        } else {
+            height
+        };
         let layer = self.layer_shell.as_ref().unwrap().get_layer_surface(
             self.base_surface.as_ref().unwrap(),
             output,
@@ -239,6 +519,7 @@ impl State {
             .xkb_state
             .get_keymap()
             .get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1);
+        let keymap = self.extra_keys.extend_keymap(&keymap);
         let keymap = CString::new(keymap).expect("Keymap should not contain interior nul bytes");
         let keymap = keymap.as_bytes_with_nul();
         let dir = std::env::var_os("XDG_RUNTIME_DIR")
@@ -254,74 +535,648 @@ impl State {
         let virtual_keyboard_manager = self.virtual_keyboard_manager.as_ref().unwrap();
         let seat = self.wl_seat.as_ref().unwrap();
         let virtual_keyboard = virtual_keyboard_manager.create_virtual_keyboard(seat, qh, ());
+        self.virtual_keyboard = Some(virtual_keyboard);
+        self.upload_keymap();
+    }
+
+    fn init_input_method(&mut self, qh: &QueueHandle<Self>) {
+        if !self.config.input_method {
+            return;
+        }
+        let Some(input_method_manager) = self.input_method_manager.as_ref() else {
+            return;
+        };
+        let seat = self.wl_seat.as_ref().unwrap();
+        let input_method = input_method_manager.get_input_method(seat, qh, ());
+        self.input_method = Some(InputMethod::new(input_method));
+    }
+
+    fn upload_keymap(&mut self) {
         let (file, size) = self.get_keymap_as_file();
+        let virtual_keyboard = self.virtual_keyboard.as_ref().unwrap();
         virtual_keyboard.keymap(
             wl_keyboard::KeymapFormat::XkbV1.into(),
             file.as_raw_fd(),
             size,
         );
-        self.virtual_keyboard = Some(virtual_keyboard);
     }
 
-    fn key_press(&self, key: u32) {
+    fn current_layout(&self) -> &str {
+        &self.layouts[self.layout_index]
+    }
+
+    /// Move to the next enabled layout, the labels and the uploaded keymap change together
+    fn switch_layout(&mut self, qh: &QueueHandle<Self>) {
+        self.layout_index = (self.layout_index + 1) % self.layouts.len();
+        let layout = self.current_layout().to_string();
+        let keymap = show_layout(&mut self.pangoui, &layout, &self.config);
+        self.xkb_state = xkb::State::new(&keymap);
+        self.altgr = has_altgr(&keymap);
+        self.extra_keys = ExtraKeys::new(&keymap);
+        if self.config.prediction {
+            self.predictor.dictionary = Dictionary::load(&layout);
+        }
+        self.upload_keymap();
+        self.send_modifiers();
+        self.update_map(qh);
+    }
+
+    /// The modifiers which change the labels, the held, latched and locked ones together
+    fn active_modifiers(&self) -> KeyModifierType {
+        self.depressed | self.latched | self.locked
+    }
+
+    fn send_modifiers(&self) {
+        let keymap = self.xkb_state.get_keymap();
+        self.virtual_keyboard.as_ref().unwrap().modifiers(
+            self.depressed.to_xkb_mask(&keymap),
+            self.latched.to_xkb_mask(&keymap),
+            self.locked.to_xkb_mask(&keymap),
+            0,
+        );
+    }
+
+    /// Returns true if the modifiers changed
+    #[must_use]
+    fn key_press(&mut self, key: u32) -> bool {
+        let keymod = KeyModifierType::from_key(key, self.altgr);
+        let virtual_keyboard = self.virtual_keyboard.as_ref().unwrap();
+        virtual_keyboard.key(1, key, KeyState::Pressed.into());
+        if keymod == KeyModifierType::NoMod {
+            if !self.depressed.is_empty() {
+                self.modifier_used = true;
+            }
+            return false;
+        }
+        // NOTE: caps lock only locks, it is never held
+        if keymod == KeyModifierType::CapsLock {
+            return false;
+        }
+        self.depressed |= keymod;
+        self.send_modifiers();
+        true
+    }
+
+    /// The input method if a text input has the focus
+    fn active_input_method(&self) -> Option<&InputMethod> {
+        self.input_method
+            .as_ref()
+            .filter(|input_method| input_method.is_active())
+    }
+
+    /// Type the text through the input method, or with the keys of the keymap which type it.
+    /// The characters the keymap cannot type are bound to extra keys for a while
+    fn type_text(&mut self, text: &str) {
+        if let Some(input_method) = self.active_input_method() {
+            input_method.commit_string(text);
+            return;
+        }
+        let keymap = self.xkb_state.get_keymap();
+        let mut used_extra_keys = false;
+        for character in text.chars() {
+            let found = inputmethod::find_keymap_key(&keymap, &character.to_string());
+            let Some((key, modifiers)) = found.or_else(|| {
+                let (key, is_new) = self.extra_keys.bind(character)?;
+                if is_new {
+                    self.upload_keymap();
+                }
+                used_extra_keys = true;
+                Some((key, KeyModifierType::NoMod))
+            }) else {
+                eprintln!("{character} is not in the keymap, skip it");
+                continue;
+            };
+            let virtual_keyboard = self.virtual_keyboard.as_ref().unwrap();
+            virtual_keyboard.modifiers(modifiers.to_xkb_mask(&keymap), 0, 0, 0);
+            virtual_keyboard.key(1, key, KeyState::Pressed.into());
+            virtual_keyboard.key(1, key, KeyState::Released.into());
+        }
+        self.send_modifiers();
+        if used_extra_keys {
+            self.restore_keymap_later();
+        }
+    }
+
+    /// Upload the base keymap after [extrakeys::RESTORE_DELAY], the extra keys typed until then
+    /// stay bound
+    fn restore_keymap_later(&mut self) {
+        let Some(loop_handle) = self.loop_handle.as_ref() else {
+            return;
+        };
+        if let Some(token) = self.keymap_restore.take() {
+            loop_handle.remove(token);
+        }
+        let timer = Timer::from_duration(extrakeys::RESTORE_DELAY);
+        self.keymap_restore = loop_handle
+            .insert_source(timer, |_, _, state| {
+                state.keymap_restore = None;
+                state.extra_keys.clear();
+                state.upload_keymap();
+                TimeoutAction::Drop
+            })
+            .ok();
+    }
+
+    /// What the key types with the modifiers now, empty for the keys without a character
+    fn key_text(&mut self, key: u32) -> String {
+        let keymap = self.xkb_state.get_keymap();
+        self.xkb_state.update_mask(
+            self.depressed.to_xkb_mask(&keymap),
+            self.latched.to_xkb_mask(&keymap),
+            self.locked.to_xkb_mask(&keymap),
+            0,
+            0,
+            0,
+        );
+        self.xkb_state
+            .key_get_utf8(key + consts::XKB_KEYCODE_OFFSET)
+    }
+
+    /// Follow the word left of the cursor through a typed normal key
+    fn track_key(&mut self, key: u32, qh: &QueueHandle<Self>) {
+        if key == otherkeys::BACKSPACE {
+            self.word.pop();
+            self.update_suggestions(qh);
+            return;
+        }
+        let text = self.key_text(key);
+        self.track_text(&text, qh);
+    }
+
+    /// Follow the word left of the cursor through typed text, a text without characters ends it
+    fn track_text(&mut self, text: &str, qh: &QueueHandle<Self>) {
+        if self.is_sensitive() {
+            self.word.clear();
+            return;
+        }
+        if text.is_empty() {
+            // NOTE: the cursor moved, the word may be incomplete, do not learn it
+            self.word.clear();
+        }
+        for character in text.chars() {
+            if prediction::is_word_char(character) {
+                self.word.push(character);
+            } else {
+                self.end_word();
+            }
+        }
+        self.update_suggestions(qh);
+    }
+
+    /// Learn the finished word, a single character is no word to suggest
+    fn end_word(&mut self) {
+        if self.config.prediction && self.word.chars().nth(1).is_some() {
+            self.predictor.user.learn(&self.word);
+            self.save_dictionary_later();
+        }
+        self.word.clear();
+    }
+
+    /// Write the user dictionary after [prediction::SAVE_DELAY], the words learned until then
+    /// are written with it, the rest is written on exit
+    fn save_dictionary_later(&mut self) {
+        let Some(loop_handle) = self.loop_handle.as_ref() else {
+            return;
+        };
+        if let Some(token) = self.dictionary_save.take() {
+            loop_handle.remove(token);
+        }
+        let timer = Timer::from_duration(prediction::SAVE_DELAY);
+        self.dictionary_save = loop_handle
+            .insert_source(timer, |_, _, state| {
+                state.dictionary_save = None;
+                state.predictor.user.save();
+                TimeoutAction::Drop
+            })
+            .ok();
+    }
+
+    /// Passwords and the like, nothing typed there is looked at
+    fn is_sensitive(&self) -> bool {
+        self.active_input_method()
+            .is_some_and(InputMethod::is_sensitive)
+    }
+
+    fn update_suggestions(&mut self, qh: &QueueHandle<Self>) {
+        let suggestions = if self.is_sensitive() {
+            vec![]
+        } else {
+            self.predictor.complete(&self.word)
+        };
+        if self.pangoui.set_suggestions(suggestions) {
+            let area = self.pangoui.title_area();
+            self.update_area(area, qh);
+        }
+    }
+
+    /// Replace the word left of the cursor with the suggestion and a space
+    fn accept_suggestion(&mut self, key: u32, qh: &QueueHandle<Self>) {
+        let Some(suggestion) = self.pangoui.suggestion(key).map(str::to_string) else {
+            return;
+        };
+        let text = format!("{suggestion} ");
+        if let Some(input_method) = self.active_input_method() {
+            input_method.replace_before(self.word.len(), &text);
+        } else {
+            let virtual_keyboard = self.virtual_keyboard.as_ref().unwrap();
+            virtual_keyboard.modifiers(0, 0, 0, 0);
+            for _ in self.word.chars() {
+                virtual_keyboard.key(1, otherkeys::BACKSPACE, KeyState::Pressed.into());
+                virtual_keyboard.key(1, otherkeys::BACKSPACE, KeyState::Released.into());
+            }
+            self.type_text(&text);
+        }
+        self.word = suggestion;
+        self.end_word();
+        self.update_suggestions(qh);
+    }
+
+    /// Hold a suggestion to forget the word, or a key with alternates to pick one, after the
+    /// repeat delay
+    fn start_long_press(&mut self, key: u32, qh: &QueueHandle<Self>) {
+        self.stop_long_press();
+        let Some(loop_handle) = self.loop_handle.as_ref() else {
+            return;
+        };
+        let timer = Timer::from_duration(Duration::from_millis(self.config.repeat_delay));
+        let qh = qh.clone();
+        let token = loop_handle
+            .insert_source(timer, move |_, _, state| {
+                state.long_press = Some((key, None));
+                if otherkeys::is_suggestion_key(key) {
+                    state.forget_suggestion(key, &qh);
+                } else {
+                    state.open_alternates(key, &qh);
+                }
+                TimeoutAction::Drop
+            })
+            .ok();
+        self.long_press = Some((key, token));
+    }
+
+    /// Returns true if the long press already acted
+    fn stop_long_press(&mut self) -> bool {
+        match self.long_press.take() {
+            Some((_, Some(token))) => {
+                if let Some(loop_handle) = self.loop_handle.as_ref() {
+                    loop_handle.remove(token);
+                }
+                false
+            }
+            Some((_, None)) => true,
+            None => false,
+        }
+    }
+
+    fn open_alternates(&mut self, key: u32, qh: &QueueHandle<Self>) {
+        let capital = self
+            .active_modifiers()
+            .intersects(KeyModifierType::Shift | KeyModifierType::CapsLock);
+        if self.pangoui.open_popup(key, capital) {
+            self.update_map(qh);
+        }
+    }
+
+    /// Keys with alternates type on release, the alternate under the position after a long press
+    fn release_alternates_key(&mut self, key: u32, position: (f64, f64), qh: &QueueHandle<Self>) {
+        if !self.stop_long_press() {
+            let _ = self.key_press(key);
+            self.track_key(key, qh);
+            if self.key_release(key) {
+                self.update_modifiers(qh);
+            }
+            return;
+        }
+        let alternate = self
+            .pangoui
+            .get_key(position)
+            .and_then(|code| self.pangoui.alternate(code))
+            .map(str::to_string);
+        let mut redraw = self.pangoui.close_popup();
+        if let Some(alternate) = alternate {
+            self.type_text(&alternate);
+            self.track_text(&alternate, qh);
+            redraw |= self.clear_latched();
+        }
+        if redraw {
+            self.update_map(qh);
+        }
+    }
+
+    /// The latched modifiers act on one key only, returns true if there were some
+    fn clear_latched(&mut self) -> bool {
+        if self.latched.is_empty() {
+            return false;
+        }
+        self.latched = KeyModifierType::NoMod;
+        self.send_modifiers();
+        true
+    }
+
+    fn forget_suggestion(&mut self, key: u32, qh: &QueueHandle<Self>) {
+        let Some(suggestion) = self.pangoui.suggestion(key).map(str::to_string) else {
+            return;
+        };
+        self.predictor.user.forget(&suggestion);
+        self.save_dictionary_later();
+        self.update_suggestions(qh);
+    }
+
+    /// Neither a unique key, a text key nor a modifier
+    fn is_normal_key(&self, key: u32) -> bool {
+        !otherkeys::is_unique_key(key)
+            && !otherkeys::is_text_key(key)
+            && KeyModifierType::from_key(key, self.altgr) == KeyModifierType::NoMod
+    }
+
+    /// Start to repeat the held key after the repeat delay, modifiers do not repeat
+    fn start_repeat(&mut self, key: u32, qh: &QueueHandle<Self>) {
+        self.stop_repeat();
+        if self.config.repeat_rate == 0 || !self.is_normal_key(key) {
+            return;
+        }
+        let Some(loop_handle) = self.loop_handle.as_ref() else {
+            return;
+        };
+        let interval = Duration::from_secs(1) / self.config.repeat_rate;
+        let timer = Timer::from_duration(Duration::from_millis(self.config.repeat_delay));
+        let qh = qh.clone();
+        self.repeat_token = loop_handle
+            .insert_source(timer, move |_, _, state| {
+                state.key_repeat(key, &qh);
+                TimeoutAction::ToDuration(interval)
+            })
+            .ok()
+            .map(|token| (key, token));
+    }
+
+    fn stop_repeat(&mut self) {
+        if let (Some(loop_handle), Some((_, token))) =
+            (self.loop_handle.as_ref(), self.repeat_token.take())
+        {
+            loop_handle.remove(token);
+        }
+    }
+
+    /// Only stop the repeat if it is the given key which repeats
+    fn stop_repeat_key(&mut self, key: u32) {
+        if matches!(self.repeat_token, Some((repeat_key, _)) if repeat_key == key) {
+            self.stop_repeat();
+        }
+    }
+
+    /// Press the key at the position, returns the key which is held now
+    fn press_at(
+        &mut self,
+        position: (f64, f64),
+        touch: bool,
+        qh: &QueueHandle<Self>,
+    ) -> Option<u32> {
+        let key = self.pangoui.get_key(position)?;
+        if otherkeys::is_alternate_key(key) {
+            // NOTE: only the finger which opened the popup picks from it
+            return None;
+        }
+        if let Some(area) = self.pangoui.press_key(key, touch) {
+            self.update_area(area, qh);
+        }
+        if otherkeys::is_text_key(key) {
+            // NOTE: the text shows at the cursor while the key is held, but never in a password
+            if let (Some(input_method), Some(text)) =
+                (self.active_input_method(), self.pangoui.commit_text(key))
+            {
+                if !input_method.is_sensitive() {
+                    input_method.set_preedit(text);
+                }
+            }
+        } else if otherkeys::is_suggestion_key(key) {
+            self.start_long_press(key, qh);
+        } else if self.pangoui.has_alternates(key) && self.is_normal_key(key) {
+            // NOTE: typed on release, a long press opens the alternates instead
+            self.start_long_press(key, qh);
+        } else if !otherkeys::is_unique_key(key) {
+            if self.key_press(key) {
+                self.update_modifiers(qh);
+            }
+            if self.is_normal_key(key) {
+                self.track_key(key, qh);
+            }
+            self.start_repeat(key, qh);
+        }
+        Some(key)
+    }
+
+    /// Release a held key, unique keys act now if the position is still on them
+    fn release_at(&mut self, key: u32, position: (f64, f64), qh: &QueueHandle<Self>) {
+        if let Some(area) = self.pangoui.release_key(key) {
+            self.update_area(area, qh);
+        }
+        if self.pangoui.has_alternates(key) && self.is_normal_key(key) {
+            self.release_alternates_key(key, position, qh);
+            return;
+        }
+        if otherkeys::is_unique_key(key) {
+            let long_pressed = self.stop_long_press();
+            if !long_pressed && self.pangoui.get_key(position) == Some(key) {
+                self.unique_key_action(key, qh);
+            }
+            return;
+        }
+        if otherkeys::is_text_key(key) {
+            if let Some(text) = self.pangoui.commit_text(key).map(str::to_string) {
+                self.type_text(&text);
+                self.track_text(&text, qh);
+            }
+            return;
+        }
+        self.stop_repeat_key(key);
+        if self.key_release(key) {
+            self.update_modifiers(qh);
+        }
+    }
+
+    /// Release a held key without acting on it, the modifiers keep their state
+    fn cancel_key(&mut self, key: u32, qh: &QueueHandle<Self>) {
+        if let Some(area) = self.pangoui.release_key(key) {
+            self.update_area(area, qh);
+        }
+        if otherkeys::is_unique_key(key) {
+            self.stop_long_press();
+            return;
+        }
+        if self.pangoui.has_alternates(key) && self.is_normal_key(key) {
+            self.stop_long_press();
+            if self.pangoui.close_popup() {
+                self.update_map(qh);
+            }
+            return;
+        }
+        if otherkeys::is_text_key(key) {
+            if let Some(input_method) = self.active_input_method() {
+                input_method.set_preedit("");
+            }
+            return;
+        }
+        self.stop_repeat_key(key);
         let virtual_keyboard = self.virtual_keyboard.as_ref().unwrap();
-        //virtual_keyboard.modifiers(1, 0, 0, 0);
+        virtual_keyboard.key(1, key, KeyState::Released.into());
+        let keymod = KeyModifierType::from_key(key, self.altgr);
+        if self.depressed.intersects(keymod) {
+            self.depressed.remove(keymod);
+            if self.depressed.is_empty() {
+                self.modifier_used = false;
+            }
+            self.send_modifiers();
+            self.update_modifiers(qh);
+        }
+    }
+
+    fn unique_key_action(&mut self, key: u32, qh: &QueueHandle<Self>) {
+        if key == otherkeys::CLOSE_KEYBOARD {
+            self.running = false;
+        } else if key == otherkeys::MIN_KEYBOARD {
+            self.is_min = !self.is_min;
+            self.min_keyboard();
+        } else if key == otherkeys::SWITCH_LAYOUT {
+            self.switch_layout(qh);
+        } else if otherkeys::is_suggestion_key(key) {
+            self.accept_suggestion(key, qh);
+        } else if key == otherkeys::FUNCTION_ROW {
+            self.pangoui.toggle_function_row();
+            self.update_map(qh);
+        } else if key == otherkeys::EMOJI_PANEL {
+            self.pangoui.toggle_emoji_panel();
+            self.update_map(qh);
+        } else if otherkeys::is_emoji_key(key) {
+            self.type_emoji(key, qh);
+        } else if otherkeys::is_page_key(key) {
+            if self.pangoui.switch_page(key) {
+                self.update_map(qh);
+            }
+        } else if self.pangoui.emoji_panel_action(key) {
+            self.update_map(qh);
+        }
+    }
+
+    /// Type the symbol of the emoji panel and put it first in the recent ones
+    fn type_emoji(&mut self, key: u32, qh: &QueueHandle<Self>) {
+        let Some(symbol) = self.pangoui.emoji(key).map(str::to_string) else {
+            return;
+        };
+        self.type_text(&symbol);
+        self.track_text(&symbol, qh);
+        if self.is_sensitive() {
+            return;
+        }
+        self.recent_emoji.push(&symbol);
+        self.pangoui
+            .set_recent_emoji(self.recent_emoji.symbols.clone());
+        self.update_map(qh);
+    }
+
+    fn key_repeat(&mut self, key: u32, qh: &QueueHandle<Self>) {
+        let virtual_keyboard = self.virtual_keyboard.as_ref().unwrap();
+        virtual_keyboard.key(1, key, KeyState::Released.into());
         virtual_keyboard.key(1, key, KeyState::Pressed.into());
+        self.track_key(key, qh);
     }
 
+    /// A tapped modifier latches, a second tap within the repeat delay locks it, a slower one
+    /// unlatches it, and a tap on a locked modifier unlocks it.
+    /// A modifier held while another key is typed acts only as held.
+    /// Returns true if the modifiers changed
     #[must_use]
     fn key_release(&mut self, key: u32) -> bool {
         let virtual_keyboard = self.virtual_keyboard.as_ref().unwrap();
         virtual_keyboard.key(1, key, KeyState::Released.into());
-        let mod_pre = self.keymode;
-        let keymod: KeyModifierType = key.into();
-        self.keymode ^= keymod;
-        if self.keymode == mod_pre {
-            false
+        let keymod = KeyModifierType::from_key(key, self.altgr);
+        if keymod == KeyModifierType::NoMod {
+            return self.clear_latched();
+        } else if keymod == KeyModifierType::CapsLock {
+            self.locked.toggle(keymod);
         } else {
-            virtual_keyboard.modifiers(self.keymode.bits(), 0, 0, 0);
-            true
+            self.depressed.remove(keymod);
+            if self.modifier_used {
+                // NOTE: it was used as a held modifier
+            } else if self.locked.contains(keymod) {
+                self.locked.remove(keymod);
+            } else if self.latched.contains(keymod) {
+                self.latched.remove(keymod);
+                let delay = Duration::from_millis(self.config.repeat_delay);
+                if self
+                    .latched_at
+                    .is_some_and(|latched_at| latched_at.elapsed() < delay)
+                {
+                    self.locked.insert(keymod);
+                }
+            } else {
+                self.latched.insert(keymod);
+                self.latched_at = Some(Instant::now());
+            }
+            if self.depressed.is_empty() {
+                self.modifier_used = false;
+            }
         }
+        self.send_modifiers();
+        true
     }
 
     fn update_map(&mut self, qh: &QueueHandle<Self>) {
-        let key_type = self.keymode;
-        let (width, height) = self.pangoui.get_size();
-        self.base_surface
-            .as_ref()
-            .unwrap()
-            .damage_buffer(0, 0, width, height);
-        self.set_buffer(qh, key_type);
-        self.base_surface.as_ref().unwrap().frame(qh, ());
-        self.base_surface
-            .as_ref()
-            .unwrap()
-            .attach(self.buffer.as_ref(), 0, 0);
-        self.base_surface.as_ref().unwrap().commit();
+        self.pangoui.damage_all();
+        self.redraw(qh);
     }
 
-    fn draw(&mut self, key_type: KeyModifierType, tmp: &File) {
-        let mut buf = std::io::BufWriter::new(tmp);
+    /// Draw the area again, the compositor takes only the area from the new buffer
+    fn update_area(&mut self, area: Area, qh: &QueueHandle<Self>) {
+        self.pangoui.damage(area);
+        self.redraw(qh);
+    }
 
-        for index in self.pangoui.ui(key_type).pixels() {
-            let [mut r, mut g, mut b, mut a] = index.0;
-            // NOTE: transparent
-            if r == 255 && g == 255 && b == 255 {
-                a = 50;
-                r = 50;
-                g = 50;
-                b = 50;
-            }
-            buf.write_all(&[r, g, b, a]).unwrap();
+    /// Draw the keys again which look different with the modifiers now
+    fn update_modifiers(&mut self, qh: &QueueHandle<Self>) {
+        self.pangoui
+            .damage_modifiers(self.active_modifiers(), self.locked);
+        self.redraw(qh);
+    }
+
+    /// Draw the damage now, or with the frame callback while the compositor has not shown
+    /// the last frame yet. The damage of a burst of events makes one frame
+    fn redraw(&mut self, qh: &QueueHandle<Self>) {
+        self.dirty |= !self.pangoui.pending_damage().is_empty();
+        if self.dirty && self.frame.is_none() {
+            self.commit_damage(qh);
+        }
+    }
+
+    fn commit_damage(&mut self, qh: &QueueHandle<Self>) {
+        // NOTE: the compositor reads all the buffers, the damage waits for a release
+        let Some(damage) = self.set_buffer(qh) else {
+            return;
+        };
+        self.dirty = false;
+        let surface = self.base_surface.as_ref().unwrap();
+        for area in damage {
+            surface.damage_buffer(area.x, area.y, area.width, area.height);
         }
-        buf.flush().unwrap();
+        self.frame = Some(surface.frame(qh, ()));
+        surface.attach(self.buffer.as_ref(), 0, 0);
+        surface.commit();
     }
 
     fn get_key_point(&self) -> Option<u32> {
         self.pangoui.get_key(self.position)
     }
+}
 
-    fn get_key_touch(&self) -> Option<u32> {
-        self.pangoui.get_key(self.touch_pos)
-    }
+#[test]
+fn tst_xkb_modifier_mask() {
+    let keymap = new_keymap("us", &Config::default());
+    assert_eq!(
+        (KeyModifierType::Shift | KeyModifierType::Ctrl).to_xkb_mask(&keymap),
+        0b101
+    );
+    assert_eq!(KeyModifierType::CapsLock.to_xkb_mask(&keymap), 0b10);
+    assert_eq!(KeyModifierType::Super.to_xkb_mask(&keymap), 1 << 6);
+    assert_eq!(KeyModifierType::AltGr.to_xkb_mask(&keymap), 1 << 7);
+    assert_eq!(KeyModifierType::NoMod.to_xkb_mask(&keymap), 0);
 }
@@ -1,8 +1,21 @@
+use crate::consts::SUGGESTION_COUNT;
+
 pub const LEFT: u32 = 105;
 pub const RIGHT: u32 = 106;
 pub const DOWN: u32 = 108;
 pub const UP: u32 = 103;
+pub const HOME: u32 = 102;
+pub const END: u32 = 107;
+pub const PAGE_UP: u32 = 104;
+pub const PAGE_DOWN: u32 = 109;
+pub const DELETE: u32 = 111;
 pub const ESC: u32 = 1;
+pub const INSERT: u32 = 110;
+/// F1 to F10 follow each other, F11 and F12 come later
+pub const F1: u32 = 59;
+pub const F11: u32 = 87;
+pub const F12: u32 = 88;
+pub const BACKSPACE: u32 = 14;
 pub const SHIFT_LEFT: u32 = 42;
 pub const SHIFT_RIGHT: u32 = 54;
 pub const MENU: u32 = 139;
@@ -14,7 +27,64 @@ pub const ALT_RIGHT: u32 = 100;
 
 pub const MIN_KEYBOARD: u32 = 999;
 pub const CLOSE_KEYBOARD: u32 = 1000;
+pub const SWITCH_LAYOUT: u32 = 1001;
+/// The suggestions of the title strip, from the left
+pub const SUGGESTION_START: u32 = 1002;
+/// Opens and closes the emoji panel
+pub const EMOJI_PANEL: u32 = 1010;
+pub const EMOJI_PREV_PAGE: u32 = 1011;
+pub const EMOJI_NEXT_PAGE: u32 = 1012;
+/// Shows and hides the row of the function keys above the main rows
+pub const FUNCTION_ROW: u32 = 1013;
+/// The category tabs of the emoji panel, the recent symbols first
+pub const EMOJI_CATEGORY_START: u32 = 1020;
+const EMOJI_CATEGORY_MAX: u32 = 32;
+/// The symbols of the emoji panel page, row by row
+pub const EMOJI_START: u32 = 1200;
+const EMOJI_MAX: u32 = 512;
+/// The alternates in the popup of a held key, from the left
+pub const ALTERNATE_START: u32 = 1100;
+const ALTERNATE_MAX: u32 = 64;
+
+/// Codes from here on are the keys which type text, far above every evdev keycode
+pub const TEXT_KEY_START: u32 = 0x10000;
+/// The keys which switch the pages get their codes from here, below the text keys
+pub const PAGE_KEY_START: u32 = 0x8000;
 
 pub fn is_unique_key(key: u32) -> bool {
-    key == MIN_KEYBOARD || key == CLOSE_KEYBOARD
+    key == MIN_KEYBOARD
+        || key == CLOSE_KEYBOARD
+        || key == SWITCH_LAYOUT
+        || key == EMOJI_PANEL
+        || key == EMOJI_PREV_PAGE
+        || key == EMOJI_NEXT_PAGE
+        || key == FUNCTION_ROW
+        || is_suggestion_key(key)
+        || is_emoji_category_key(key)
+        || is_emoji_key(key)
+        || is_page_key(key)
+}
+
+pub fn is_emoji_category_key(key: u32) -> bool {
+    (EMOJI_CATEGORY_START..EMOJI_CATEGORY_START + EMOJI_CATEGORY_MAX).contains(&key)
+}
+
+pub fn is_emoji_key(key: u32) -> bool {
+    (EMOJI_START..EMOJI_START + EMOJI_MAX).contains(&key)
+}
+
+pub fn is_suggestion_key(key: u32) -> bool {
+    (SUGGESTION_START..SUGGESTION_START + SUGGESTION_COUNT as u32).contains(&key)
+}
+
+pub fn is_alternate_key(key: u32) -> bool {
+    (ALTERNATE_START..ALTERNATE_START + ALTERNATE_MAX).contains(&key)
+}
+
+pub fn is_text_key(key: u32) -> bool {
+    key >= TEXT_KEY_START
+}
+
+pub fn is_page_key(key: u32) -> bool {
+    (PAGE_KEY_START..TEXT_KEY_START).contains(&key)
 }
@@ -1,28 +1,206 @@
+mod emojipanel;
 mod mainkeyboard;
+mod popup;
 mod smallkeyboard;
 //use std::f64::consts::PI;
 
+use std::collections::HashMap;
+
 use cairo::Context;
-use smallkeyboard::{draw_number_keyboard, find_keycode_from_smallkeyboard};
+use smallkeyboard::{
+    draw_extra_btn, draw_number_keyboard, find_keycode_from_smallkeyboard, number_columns,
+    number_step,
+};
 
-use crate::{consts::KEYBOARD_TITLE, otherkeys, pangoui::mainkeyboard::draw_main_keyboard};
+use crate::{
+    consts::{KEYBOARD_TITLE, SUGGESTION_COUNT},
+    emoji::EmojiCategory,
+    keyboardlayouts::{
+        function_row, navigation_cluster, KeyboardLayout, LayoutKey, Purpose, MAIN_PAGE,
+    },
+    otherkeys,
+    pangoui::mainkeyboard::draw_main_keyboard,
+};
 
-use self::mainkeyboard::find_keycode_from_mainkeyboard;
+use self::emojipanel::EmojiPanel;
+use self::mainkeyboard::{find_keycode_from_mainkeyboard, key_look};
+use self::popup::{draw_bubble, draw_popup, Popup};
 
 use super::KeyModifierType;
 
+use xkbcommon::xkb;
+
 use crate::consts::{EXCULDE_ZONE_RIGHT, EXCULDE_ZONE_TOP};
 
+/// How much the white of the keyboard covers what is under it
+const BACKGROUND_ALPHA: f64 = 50.0 / 255.0;
+
+/// The rows of a block of square keys and its left edge
+type Block<'a> = (&'a [Vec<LayoutKey>], f64);
+
+// NOTE: from left to right, the buttons at the right of the title
+const TITLE_KEYS: [u32; 5] = [
+    otherkeys::SWITCH_LAYOUT,
+    otherkeys::EMOJI_PANEL,
+    otherkeys::FUNCTION_ROW,
+    otherkeys::MIN_KEYBOARD,
+    otherkeys::CLOSE_KEYBOARD,
+];
+
 #[derive(Debug, Default)]
 pub struct PangoUi {
     width: i32,
     height: i32,
+    /// the right alt key is AltGr in the keymap of the layout
+    altgr: bool,
+    /// the layout file with the keymap labels, before the purpose changes it
+    base_layout: KeyboardLayout,
+    purpose: Purpose,
+    main_layout: KeyboardLayout,
+    /// add the navigation cluster to the layouts without their own navigation keys
+    navigation: bool,
+    /// the page shown instead of the main rows of every layout, kept when switching layouts
+    pages: HashMap<String, String>,
+    /// the function keys are shown above the main rows
+    show_function_row: bool,
+    /// the rows drawn as the main keys, the function row and the main rows or the page
+    rows: Vec<Vec<LayoutKey>>,
+    /// shown in the title strip instead of the title
+    suggestions: Vec<String>,
+    popup: Option<Popup>,
+    emoji_panel: EmojiPanel,
+    /// the emoji panel is drawn instead of the main keys
+    show_emoji: bool,
+    /// the held keys, drawn like the held modifiers
+    pressed: Vec<u32>,
+    /// the narrow key held with a finger, its label is shown above it
+    bubble: Option<u32>,
+    /// the held and the locked modifiers the surface was drawn with
+    drawn_modifiers: (KeyModifierType, KeyModifierType),
+    /// the parts of the next frame to draw again
+    damage: Vec<Area>,
+}
+
+/// A rectangle of the surface in pixels, the part to draw again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Area {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Area {
+    /// The whole pixels covering the rectangle
+    fn covering(x: f64, y: f64, width: f64, height: f64) -> Self {
+        let (left, top) = (x.floor() as i32, y.floor() as i32);
+        Area {
+            x: left,
+            y: top,
+            width: (x + width).ceil() as i32 - left,
+            height: (y + height).ceil() as i32 - top,
+        }
+    }
+
+    /// The smallest area covering both
+    pub fn union(self, other: Area) -> Area {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Area {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// What changes the look of the keys
+pub(super) struct Highlight<'a> {
+    /// the active modifiers, they choose the labels
+    key_type: KeyModifierType,
+    locked: KeyModifierType,
+    pressed: &'a [u32],
+}
+
+/// Replace what is under the path with the background, the compositor shows through it
+pub(super) fn fill_background(content: &Context) {
+    content.save().unwrap();
+    content.set_operator(cairo::Operator::Source);
+    content.set_source_rgba(1.0, 1.0, 1.0, BACKGROUND_ALPHA);
+    content.fill_preserve().unwrap();
+    content.restore().unwrap();
+}
+
+/// Skip the rows outside the clip, they would draw nothing.
+/// NOTE: only up and down, a label may reach into the next key of its row
+fn outside_clip(content: &Context, y: f64, height: f64) -> bool {
+    content
+        .clip_extents()
+        .is_ok_and(|(_, clip_top, _, clip_bottom)| y + height < clip_top || y > clip_bottom)
 }
 
 fn contain_mode(key_type: KeyModifierType, mode: KeyModifierType) -> bool {
     key_type == key_type | mode
 }
 
+// TODO: cap and shift
+#[derive(Clone, Copy)]
+enum KeyType {
+    Normal,
+    Cap,
+    Shift,
+    AltGr,
+}
+
+impl From<KeyModifierType> for KeyType {
+    fn from(value: KeyModifierType) -> Self {
+        if contain_mode(value, KeyModifierType::AltGr) {
+            KeyType::AltGr
+        } else if contain_mode(value, KeyModifierType::Shift) {
+            KeyType::Shift
+        } else if contain_mode(value, KeyModifierType::CapsLock) {
+            KeyType::Cap
+        } else {
+            KeyType::Normal
+        }
+    }
+}
+
+fn get_key_text(map: &LayoutKey, keymode: KeyModifierType) -> &str {
+    let text = match keymode.into() {
+        KeyType::Normal => None,
+        KeyType::Cap => map.cap.as_ref(),
+        KeyType::Shift => map.shift.as_ref().or(map.cap.as_ref()),
+        KeyType::AltGr => map.altgr.as_ref(),
+    };
+    text.unwrap_or(&map.text)
+}
+
+/// The suggestions share the title strip left of the buttons
+fn draw_suggestions(
+    context: &Context,
+    pangolayout: &pango::Layout,
+    width: i32,
+    suggestions: &[String],
+) {
+    let step = (width as f64 - EXCULDE_ZONE_RIGHT) / SUGGESTION_COUNT as f64;
+    for (index, suggestion) in suggestions.iter().enumerate() {
+        let x = step * index as f64;
+        if index > 0 {
+            context.move_to(x, 0.0);
+            context.line_to(x, EXCULDE_ZONE_TOP);
+        }
+        pangolayout.set_text(suggestion);
+        let (textwidth, _) = pangolayout.pixel_size();
+        context.save().unwrap();
+        context.move_to(x + ((step - textwidth as f64) / 2.0).max(0.0), 0.0);
+        pangocairo::show_layout(context, pangolayout);
+        context.restore().unwrap();
+    }
+    context.stroke().unwrap();
+}
+
 fn draw_title(context: &Context, pangolayout: &pango::Layout, width: i32) {
     pangolayout.set_text(KEYBOARD_TITLE);
     let (textwidth, _) = pangolayout.pixel_size();
@@ -34,18 +212,50 @@ fn draw_title(context: &Context, pangolayout: &pango::Layout, width: i32) {
 }
 
 impl PangoUi {
+    /// Draw the `damage` into the surface, the rest of it keeps the frame drawn before.
+    ///
+    /// `key_type` are all the active modifiers, the `locked` ones are drawn darker
     pub(crate) fn ui(
-        &self,
+        &mut self,
+        surface: &cairo::ImageSurface,
+        damage: &[Area],
         key_type: KeyModifierType,
-    ) -> image::ImageBuffer<image::Rgba<u8>, Vec<u8>> {
-        let height = self.height;
-        let width = self.width;
-        let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, width, height).unwrap();
-        let cr = cairo::Context::new(&surface).unwrap();
-        cr.set_source_rgb(1_f64, 1_f64, 1_f64);
-        cr.paint().unwrap();
+        locked: KeyModifierType,
+    ) {
+        if damage.is_empty() {
+            return;
+        }
+        let cr = cairo::Context::new(surface).unwrap();
+        // NOTE: one clip of all the areas, every area drawn on its own draws the rows many times
+        for &Area {
+            x,
+            y,
+            width,
+            height,
+        } in damage
+        {
+            cr.rectangle(x as f64, y as f64, width as f64, height as f64);
+        }
+        cr.clip();
+        self.draw(&cr, key_type, locked);
+        self.drawn_modifiers = (key_type, locked);
+    }
+
+    /// Draw everything, the clip of the context limits it
+    fn draw(&self, cr: &Context, key_type: KeyModifierType, locked: KeyModifierType) {
+        let (width, height) = (self.width, self.height);
+        cr.rectangle(0.0, 0.0, width as f64, height as f64);
+        fill_background(cr);
+        cr.new_path();
+        // NOTE: the number block may be empty, it cannot be the one to set the pen color
+        cr.set_source_rgb(0_f64, 0_f64, 0_f64);
+        let highlight = Highlight {
+            key_type,
+            locked,
+            pressed: &self.pressed,
+        };
         let font_size = 23;
-        let pangolayout = pangocairo::create_layout(&cr);
+        let pangolayout = pangocairo::create_layout(cr);
         let mut desc = pango::FontDescription::new();
         desc.set_family("Sans");
         desc.set_weight(pango::Weight::Bold);
@@ -53,17 +263,105 @@ impl PangoUi {
         desc.set_size(font_size * pango::SCALE);
         pangolayout.set_font_description(Some(&desc));
 
-        draw_number_keyboard(&cr, &pangolayout, width, height, 27, key_type);
-        draw_main_keyboard(&cr, &pangolayout, height, 27, key_type);
-        draw_title(&cr, &pangolayout, width);
+        let (step, blocks) = self.blocks();
+        for (block, start_x) in blocks {
+            draw_number_keyboard(cr, &pangolayout, block, start_x, step, 27, &highlight);
+        }
+        if self.show_emoji {
+            let (step, columns) = self.emoji_grid();
+            self.emoji_panel.draw(cr, &pangolayout, step, columns);
+        } else {
+            draw_main_keyboard(
+                cr,
+                &pangolayout,
+                self.rows(),
+                self.altgr,
+                self.main_step(),
+                27,
+                &highlight,
+            );
+        }
+        if self.suggestions.is_empty() {
+            draw_title(cr, &pangolayout, width);
+        } else {
+            draw_suggestions(cr, &pangolayout, width, &self.suggestions);
+        }
+        draw_extra_btn(
+            cr,
+            &pangolayout,
+            width,
+            &[&self.base_layout.layout, "☺", "Fn", "-", "x"],
+        );
+        let bubble = self
+            .bubble
+            .and_then(|key| Some((self.layout_key(key)?, self.bubble_rect(key)?)));
+        if let Some((map, rect)) = bubble {
+            draw_bubble(cr, &pangolayout, rect, get_key_text(map, key_type));
+        }
+        if let Some(popup) = self.popup.as_ref() {
+            draw_popup(cr, &pangolayout, popup);
+        }
+    }
+
+    pub fn damage(&mut self, area: Area) {
+        self.damage.push(area);
+    }
+
+    pub fn damage_all(&mut self) {
+        self.damage = vec![Area {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }];
+    }
+
+    /// The parts of the next frame to draw again
+    pub fn pending_damage(&self) -> &[Area] {
+        &self.damage
+    }
+
+    pub fn take_damage(&mut self) -> Vec<Area> {
+        std::mem::take(&mut self.damage)
+    }
 
-        use std::io::Cursor;
-        let mut buff = Cursor::new(Vec::new());
+    /// The suggestions or the title left of the buttons
+    pub fn title_area(&self) -> Area {
+        Area {
+            x: 0,
+            y: 0,
+            width: self.width - EXCULDE_ZONE_RIGHT as i32,
+            height: EXCULDE_ZONE_TOP as i32,
+        }
+    }
 
-        surface.write_to_png(&mut buff).unwrap();
-        image::load_from_memory_with_format(buff.get_ref(), image::ImageFormat::Png)
-            .unwrap()
-            .to_rgba8()
+    /// Damage the keys whose label or highlight changes with the modifiers
+    pub fn damage_modifiers(&mut self, key_type: KeyModifierType, locked: KeyModifierType) {
+        let (drawn_type, drawn_locked) = self.drawn_modifiers;
+        let drawn = Highlight {
+            key_type: drawn_type,
+            locked: drawn_locked,
+            pressed: &[],
+        };
+        let now = Highlight {
+            key_type,
+            locked,
+            pressed: &[],
+        };
+        let changed: Vec<u32> = self
+            .rows()
+            .iter()
+            .chain(self.main_layout.numbers.iter())
+            .chain(self.main_layout.navigation.iter().flatten())
+            .flatten()
+            .filter(|map| key_look(map, &drawn, self.altgr) != key_look(map, &now, self.altgr))
+            .map(|map| map.key)
+            .collect();
+        for key in changed {
+            if let Some(area) = self.key_area(key) {
+                self.damage.push(area);
+            }
+        }
     }
 
     pub fn set_size(&mut self, (width, height): (i32, i32)) {
@@ -75,36 +373,573 @@ impl PangoUi {
         (self.width, self.height)
     }
 
+    /// Takes effect with the next [PangoUi::set_layout]
+    pub fn set_navigation(&mut self, navigation: bool) {
+        self.navigation = navigation;
+    }
+
+    pub fn set_layout(&mut self, layout: KeyboardLayout, altgr: bool) {
+        self.altgr = altgr;
+        self.base_layout = layout;
+        if self.navigation && self.base_layout.navigation.is_none() {
+            self.base_layout.navigation = Some(navigation_cluster());
+        }
+        self.main_layout = self.base_layout.for_purpose(self.purpose);
+        self.update_rows();
+    }
+
+    /// The labels come from what the keymap types
+    pub fn apply_keymap(&mut self, keymap: &xkb::Keymap) {
+        self.base_layout.apply_keymap_labels(keymap);
+        self.main_layout = self.base_layout.for_purpose(self.purpose);
+        self.update_rows();
+    }
+
+    /// Returns true if the keys changed
+    pub fn set_purpose(&mut self, purpose: Purpose) -> bool {
+        if self.purpose == purpose {
+            return false;
+        }
+        self.purpose = purpose;
+        self.main_layout = self.base_layout.for_purpose(purpose);
+        self.update_rows();
+        true
+    }
+
+    /// Returns true if the suggestions changed
+    pub fn set_suggestions(&mut self, suggestions: Vec<String>) -> bool {
+        if self.suggestions == suggestions {
+            return false;
+        }
+        self.suggestions = suggestions;
+        true
+    }
+
+    pub fn suggestion(&self, key: u32) -> Option<&str> {
+        let index = key.checked_sub(otherkeys::SUGGESTION_START)?;
+        self.suggestions.get(index as usize).map(String::as_str)
+    }
+
+    /// The size of their keys and the blocks of square keys with their left edge, the
+    /// navigation block is left of the number block
+    fn blocks(&self) -> (f64, [Block<'_>; 2]) {
+        let numbers = self.main_layout.numbers.as_slice();
+        let navigation = self.main_layout.navigation.as_deref().unwrap_or_default();
+        let step = number_step(&[numbers, navigation], self.width, self.height);
+        let numbers_x = self.width as f64 - number_columns(numbers) as f64 * step;
+        let navigation_x = numbers_x - number_columns(navigation) as f64 * step;
+        (step, [(navigation, navigation_x), (numbers, numbers_x)])
+    }
+
+    /// The left of the blocks, the main keys or the emoji panel are left of it
+    fn number_block_start(&self) -> i32 {
+        let (_, [(_, start_x), _]) = self.blocks();
+        start_x as i32
+    }
+
+    /// The cell size and the columns of the emoji panel, the cells are as big as the main keys
+    fn emoji_grid(&self) -> (f64, usize) {
+        let step = (self.height - EXCULDE_ZONE_TOP as i32) / 4;
+        let columns = self.number_block_start() / step.max(1);
+        (step as f64, columns.max(1) as usize)
+    }
+
+    pub fn set_emoji_categories(&mut self, categories: Vec<EmojiCategory>) {
+        self.emoji_panel = EmojiPanel::new(categories);
+    }
+
+    pub fn set_recent_emoji(&mut self, symbols: Vec<String>) {
+        self.emoji_panel.set_recent(symbols);
+    }
+
+    pub fn toggle_emoji_panel(&mut self) {
+        self.show_emoji = !self.show_emoji;
+        self.emoji_panel.reset();
+    }
+
+    /// Returns true if the panel changed
+    pub fn emoji_panel_action(&mut self, key: u32) -> bool {
+        let (_, columns) = self.emoji_grid();
+        match key {
+            otherkeys::EMOJI_PREV_PAGE => self.emoji_panel.flip(false, columns),
+            otherkeys::EMOJI_NEXT_PAGE => self.emoji_panel.flip(true, columns),
+            _ => return self.emoji_panel.set_category(key),
+        }
+        true
+    }
+
+    pub fn emoji(&self, key: u32) -> Option<&str> {
+        let (_, columns) = self.emoji_grid();
+        self.emoji_panel.symbol(key, columns)
+    }
+
+    /// The main rows, or the rows of the page shown instead of them, below the function row
+    /// if it is shown
+    fn rows(&self) -> &[Vec<LayoutKey>] {
+        &self.rows
+    }
+
+    /// Collect the main rows, or the rows of the page shown instead of them, below the function
+    /// row
+    fn update_rows(&mut self) {
+        let rows = self
+            .pages
+            .get(&self.base_layout.layout)
+            .and_then(|page| self.main_layout.pages.get(page))
+            .unwrap_or(&self.main_layout.rows);
+        let function_row = self.show_function_row.then(function_row);
+        self.rows = function_row
+            .into_iter()
+            .chain(rows.iter().cloned())
+            .collect();
+    }
+
+    /// The height of a main row and the width of a key two half steps wide, the rows fill the
+    /// height unless they reach into the blocks right of them, their keys are smaller then
+    fn main_step(&self) -> i32 {
+        let step = (self.height - EXCULDE_ZONE_TOP as i32) / self.rows.len().max(1) as i32;
+        let half_steps = self
+            .rows
+            .iter()
+            .flatten()
+            .map(|key| key.start_pos + key.width)
+            .max()
+            .unwrap_or(0);
+        let fit = self.number_block_start() * 2 / (half_steps as i32).max(1);
+        step.min(fit).max(1)
+    }
+
+    /// Show the page of the page key, returns true if the page changed
+    pub fn switch_page(&mut self, key: u32) -> bool {
+        let Some(page) = self.main_layout.page_name(key) else {
+            return false;
+        };
+        let changed = if page == MAIN_PAGE || !self.main_layout.pages.contains_key(page) {
+            self.pages.remove(&self.base_layout.layout).is_some()
+        } else {
+            let page = page.to_string();
+            let layout = self.base_layout.layout.clone();
+            self.pages.insert(layout, page.clone()) != Some(page)
+        };
+        if changed {
+            self.update_rows();
+        }
+        changed
+    }
+
+    pub fn toggle_function_row(&mut self) {
+        self.show_function_row = !self.show_function_row;
+        self.update_rows();
+    }
+
+    fn layout_key(&self, key: u32) -> Option<&LayoutKey> {
+        self.rows()
+            .iter()
+            .chain(self.main_layout.numbers.iter())
+            .chain(self.main_layout.navigation.iter().flatten())
+            .flatten()
+            .find(|map| map.key == key)
+    }
+
+    pub fn has_alternates(&self, key: u32) -> bool {
+        self.layout_key(key)
+            .is_some_and(|map| !map.alternates.is_empty())
+    }
+
+    /// Left, top, width and the step of the key, the step is its height
+    fn key_rect(&self, key: u32) -> Option<(f64, f64, f64, f64)> {
+        // NOTE: the main keyboard is drawn with a whole pixel step
+        let step = self.main_step() as f64;
+        for (line, row) in self.rows().iter().enumerate() {
+            if let Some(map) = row.iter().find(|map| map.key == key) {
+                return Some((
+                    step * map.start_pos as f64 / 2.0,
+                    step * line as f64 + EXCULDE_ZONE_TOP,
+                    step * map.width as f64 / 2.0,
+                    step,
+                ));
+            }
+        }
+        let (step, blocks) = self.blocks();
+        for (block, start_x) in blocks {
+            for (line, row) in block.iter().enumerate() {
+                if let Some(map) = row.iter().find(|map| map.key == key) {
+                    return Some((
+                        start_x + step * map.start_pos as f64 / 2.0,
+                        step * line as f64 + EXCULDE_ZONE_TOP,
+                        step * map.width as f64 / 2.0,
+                        step,
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    /// Show the alternates of the key, in capitals for the capital letters
+    pub fn open_popup(&mut self, key: u32, capital: bool) -> bool {
+        let Some(map) = self.layout_key(key) else {
+            return false;
+        };
+        let mut alternates = map.alternates.clone();
+        if alternates.is_empty() {
+            return false;
+        }
+        if capital {
+            alternates = alternates.iter().map(|text| text.to_uppercase()).collect();
+        }
+        let Some((x, y, _, step)) = self.key_rect(key) else {
+            return false;
+        };
+        self.popup = Some(Popup::new(key, alternates, (x, y, step), self.width));
+        // NOTE: the popup shows the key already
+        if self.bubble == Some(key) {
+            self.bubble = None;
+        }
+        true
+    }
+
+    /// Over the key and half a key wider, above it if there is room
+    fn bubble_rect(&self, key: u32) -> Option<(f64, f64, f64, f64)> {
+        let (x, y, width, step) = self.key_rect(key)?;
+        let bubble_width = width + step / 2.0;
+        let bubble_x = (x - step / 4.0).clamp(0.0, (self.width as f64 - bubble_width).max(0.0));
+        Some((bubble_x, (y - step).max(0.0), bubble_width, step))
+    }
+
+    /// The area of the key, with its bubble if it has one
+    fn key_area(&self, key: u32) -> Option<Area> {
+        let (x, y, width, height) = self.key_rect(key)?;
+        let area = Area::covering(x, y, width, height);
+        let bubble = (self.bubble == Some(key))
+            .then(|| self.bubble_rect(key))
+            .flatten()
+            .map(|(x, y, width, height)| Area::covering(x, y, width, height));
+        let Area {
+            x,
+            y,
+            width,
+            height,
+        } = bubble.map_or(area, |bubble| area.union(bubble));
+        // NOTE: one more pixel around for the border lines, but inside the surface
+        let (left, top) = ((x - 1).max(0), (y - 1).max(0));
+        Some(Area {
+            x: left,
+            y: top,
+            width: (x + width + 1).min(self.width) - left,
+            height: (y + height + 1).min(self.height) - top,
+        })
+    }
+
+    /// Highlight the key while it is held, a narrow main key held with a finger gets a bubble.
+    /// Returns the area to draw again, None if nothing changed
+    pub fn press_key(&mut self, key: u32, touch: bool) -> Option<Area> {
+        if self.pressed.contains(&key) {
+            return None;
+        }
+        self.key_rect(key)?;
+        self.pressed.push(key);
+        let narrow = self
+            .rows()
+            .iter()
+            .flatten()
+            .any(|map| map.key == key && map.width <= 2);
+        if !(touch && narrow && self.popup_key() != Some(key)) {
+            return self.key_area(key);
+        }
+        // NOTE: one bubble at a time, the one of another finger goes away
+        let replaced = self.bubble.and_then(|bubble| self.key_area(bubble));
+        self.bubble = Some(key);
+        let area = self.key_area(key)?;
+        Some(replaced.map_or(area, |replaced| replaced.union(area)))
+    }
+
+    /// Returns the area to draw again, None if the key was not highlighted
+    pub fn release_key(&mut self, key: u32) -> Option<Area> {
+        let index = self.pressed.iter().position(|pressed| *pressed == key)?;
+        let area = self.key_area(key);
+        self.pressed.remove(index);
+        if self.bubble == Some(key) {
+            self.bubble = None;
+        }
+        area
+    }
+
+    /// Returns true if there was a popup
+    pub fn close_popup(&mut self) -> bool {
+        self.popup.take().is_some()
+    }
+
+    /// The key whose popup is open
+    pub fn popup_key(&self) -> Option<u32> {
+        self.popup.as_ref().map(|popup| popup.key)
+    }
+
+    pub fn alternate(&self, key: u32) -> Option<&str> {
+        let index = key.checked_sub(otherkeys::ALTERNATE_START)?;
+        let popup = self.popup.as_ref()?;
+        popup.alternates.get(index as usize).map(String::as_str)
+    }
+
+    pub fn commit_text(&self, key: u32) -> Option<&str> {
+        self.main_layout.commit_text(key)
+    }
+
     pub fn get_key(&self, (pos_x, pos_y): (f64, f64)) -> Option<u32> {
+        if let Some(index) = self
+            .popup
+            .as_ref()
+            .and_then(|popup| popup.find((pos_x, pos_y)))
+        {
+            return Some(otherkeys::ALTERNATE_START + index as u32);
+        }
         let (pos_x, pos_y) = (pos_x as i32, pos_y as i32);
-        let exclude_zone = EXCULDE_ZONE_TOP as i32;
-        let step = (self.height - exclude_zone) / 3;
-        let x_1 = self.width - 4 * step;
-        let x_4 = self.width - step;
         let x_exclude = self.width - EXCULDE_ZONE_RIGHT as i32;
         if pos_y < EXCULDE_ZONE_TOP as i32 {
             if pos_x < x_exclude {
-                return None;
+                let step = (x_exclude / SUGGESTION_COUNT as i32).max(1);
+                let index = (pos_x / step) as usize;
+                return (index < self.suggestions.len())
+                    .then_some(otherkeys::SUGGESTION_START + index as u32);
             }
             let step_right = EXCULDE_ZONE_TOP as i32;
             let right_w = pos_x - x_exclude;
-            if right_w / step_right == 0 {
-                return Some(otherkeys::MIN_KEYBOARD);
-            } else {
-                return Some(otherkeys::CLOSE_KEYBOARD);
-            }
+            return TITLE_KEYS.get((right_w / step_right) as usize).copied();
+        }
+        let x_1 = self.number_block_start();
+        if pos_x < x_1 && self.show_emoji {
+            let (step, columns) = self.emoji_grid();
+            return self
+                .emoji_panel
+                .find((pos_x as f64, pos_y as f64), step, columns);
         }
         if pos_x < x_1 {
-            let step = (self.height - exclude_zone) / 4;
-            return find_keycode_from_mainkeyboard((pos_x, pos_y), step);
-        } else if pos_x > x_4 {
-            match (pos_y - exclude_zone) / step {
-                0 => return Some(12),
-                1 => return Some(11),
-                2 => return Some(13),
-                _ => return None,
-            }
+            let step = self.main_step();
+            return find_keycode_from_mainkeyboard(self.rows(), (pos_x, pos_y), step);
         }
-        Some(find_keycode_from_smallkeyboard((pos_x, pos_y), x_1, step))
+        let (step, blocks) = self.blocks();
+        blocks.iter().find_map(|(block, start_x)| {
+            find_keycode_from_smallkeyboard(block, (pos_x, pos_y), *start_x as i32, step as i32)
+        })
+    }
+}
+
+#[test]
+fn tst_press_key() {
+    let mut ui = PangoUi::default();
+    ui.set_layout(
+        crate::keyboardlayouts::Layouts::EnglishUs
+            .get_layout()
+            .unwrap(),
+        false,
+    );
+    ui.set_size((1920, 300));
+    let q_area = ui.press_key(16, true).unwrap();
+    // NOTE: the bubble of a top row key covers the title strip
+    assert_eq!(q_area.y, 0);
+    assert_eq!(ui.press_key(16, true), None);
+    // NOTE: the bubble of q goes away, its area is drawn again too
+    let h_area = ui.press_key(35, true).unwrap();
+    assert_eq!(h_area.union(q_area), h_area);
+    assert_eq!(ui.bubble, Some(35));
+    let tab_area = ui.press_key(15, true).unwrap();
+    assert_eq!(tab_area.height, ui.main_step() + 2);
+    assert_eq!(ui.bubble, Some(35));
+    assert_eq!(
+        ui.release_key(16).map(|area| area.height),
+        Some(ui.main_step() + 2)
+    );
+    assert_eq!(ui.release_key(16), None);
+    let h_release = ui.release_key(35).unwrap();
+    assert_eq!(h_area.union(h_release), h_area);
+    assert!(h_release.y > 0);
+    assert_eq!(ui.bubble, None);
+}
+
+#[test]
+fn tst_damage_modifiers() {
+    let mut ui = PangoUi::default();
+    ui.set_layout(
+        crate::keyboardlayouts::Layouts::EnglishUs
+            .get_layout()
+            .unwrap(),
+        false,
+    );
+    ui.set_size((1920, 300));
+    let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, 1920, 300).unwrap();
+    ui.damage_all();
+    let damage = ui.take_damage();
+    ui.ui(
+        &surface,
+        &damage,
+        KeyModifierType::NoMod,
+        KeyModifierType::NoMod,
+    );
+    assert!(ui.pending_damage().is_empty());
+    ui.damage_modifiers(KeyModifierType::NoMod, KeyModifierType::NoMod);
+    assert!(ui.pending_damage().is_empty());
+
+    // NOTE: the letters, the digits and both shift keys, but not space or the number block
+    ui.damage_modifiers(KeyModifierType::Shift, KeyModifierType::NoMod);
+    let damaged = ui.pending_damage().to_vec();
+    let space = ui.key_area(57).unwrap();
+    assert!(damaged.contains(&ui.key_area(16).unwrap()));
+    assert!(damaged.contains(&ui.key_area(42).unwrap()));
+    assert!(!damaged.contains(&space));
+    assert!(damaged.len() < ui.rows().iter().flatten().count());
+    ui.ui(
+        &surface,
+        &damaged,
+        KeyModifierType::Shift,
+        KeyModifierType::NoMod,
+    );
+    ui.take_damage();
+    ui.damage_modifiers(KeyModifierType::Shift, KeyModifierType::NoMod);
+    assert!(ui.pending_damage().is_empty());
+}
+
+/// The frame time of drawing into the surface, against encoding it to PNG, decoding it and
+/// copying it pixel by pixel as before. `cargo test --release tst_frame_time -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn tst_frame_time() {
+    use std::time::Instant;
+    let frames = 50;
+    let mut ui = PangoUi::default();
+    ui.set_layout(
+        crate::keyboardlayouts::Layouts::EnglishUs
+            .get_layout()
+            .unwrap(),
+        false,
+    );
+    ui.set_size((1920, 300));
+    let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, 1920, 300).unwrap();
+
+    let start = Instant::now();
+    for _ in 0..frames {
+        ui.damage_all();
+        let damage = ui.take_damage();
+        ui.ui(
+            &surface,
+            &damage,
+            KeyModifierType::NoMod,
+            KeyModifierType::NoMod,
+        );
+    }
+    let direct = start.elapsed() / frames;
+
+    let start = Instant::now();
+    for _ in 0..frames {
+        ui.damage_all();
+        let damage = ui.take_damage();
+        ui.ui(
+            &surface,
+            &damage,
+            KeyModifierType::NoMod,
+            KeyModifierType::NoMod,
+        );
+        let mut png = Vec::new();
+        surface.write_to_png(&mut png).unwrap();
+        let mut decoded = cairo::ImageSurface::create_from_png(&mut png.as_slice()).unwrap();
+        let mut pixels = Vec::new();
+        for pixel in decoded.data().unwrap().chunks(4) {
+            pixels.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+        }
+        assert_eq!(pixels.len(), 1920 * 300 * 4);
+    }
+    let round_trip = start.elapsed() / frames;
+
+    let start = Instant::now();
+    for index in 0..frames {
+        let key_type = if index % 2 == 0 {
+            KeyModifierType::Shift
+        } else {
+            KeyModifierType::NoMod
+        };
+        ui.damage_modifiers(key_type, KeyModifierType::NoMod);
+        let damage = ui.take_damage();
+        ui.ui(&surface, &damage, key_type, KeyModifierType::NoMod);
+    }
+    let shift = start.elapsed() / frames;
+
+    println!("whole frame {direct:?}, with the PNG round-trip {round_trip:?}, shift {shift:?}");
+    assert!(direct < round_trip);
+}
+
+#[test]
+fn tst_narrow_number_block() {
+    let mut ui = PangoUi::default();
+    ui.set_layout(
+        crate::keyboardlayouts::Layouts::EnglishUs
+            .get_layout()
+            .unwrap(),
+        false,
+    );
+    ui.set_size((400, 300));
+    let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, 400, 300).unwrap();
+    ui.damage_all();
+    let damage = ui.take_damage();
+    ui.ui(
+        &surface,
+        &damage,
+        KeyModifierType::NoMod,
+        KeyModifierType::NoMod,
+    );
+    // NOTE: the block takes half of the width, its keys are smaller than the rows allow
+    assert_eq!(ui.number_block_start(), 200);
+    let (x, y, width, _) = ui.key_rect(ui.main_layout.numbers[0][0].key).unwrap();
+    assert_eq!(
+        ui.get_key((x + width / 2.0, y + 1.0)),
+        Some(ui.main_layout.numbers[0][0].key)
+    );
+    // NOTE: the main rows get the rest of the width, no key reaches into the block
+    for map in ui.rows().iter().flatten() {
+        let (x, y, width, height) = ui.key_rect(map.key).unwrap();
+        assert!(x + width <= ui.number_block_start() as f64);
+        assert_eq!(
+            ui.get_key((x + width / 2.0, y + height / 2.0)),
+            Some(map.key)
+        );
+    }
+}
+
+#[test]
+fn tst_navigation_block() {
+    let mut ui = PangoUi::default();
+    ui.set_size((2000, 300));
+    ui.set_layout(
+        crate::keyboardlayouts::Layouts::EnglishUs
+            .get_layout()
+            .unwrap(),
+        false,
+    );
+    let digit = ui.main_layout.numbers[0][0].key;
+    let digit_rect = ui.key_rect(digit).unwrap();
+    ui.set_navigation(true);
+    ui.set_layout(
+        crate::keyboardlayouts::Layouts::EnglishUs
+            .get_layout()
+            .unwrap(),
+        false,
+    );
+    // NOTE: the navigation block goes left of the number block, the digits keep their size
+    assert_eq!(ui.key_rect(digit), Some(digit_rect));
+    let (x, y, width, _) = ui.key_rect(otherkeys::HOME).unwrap();
+    assert!(x + width <= digit_rect.0);
+    assert_eq!(
+        ui.get_key((x + width / 2.0, y + 1.0)),
+        Some(otherkeys::HOME)
+    );
+    assert!(ui.number_block_start() <= x as i32);
+    // NOTE: on a laptop the main rows end where the navigation block begins
+    ui.set_size((1366, 300));
+    assert!(ui.main_step() < (300 - EXCULDE_ZONE_TOP as i32) / ui.rows().len() as i32);
+    for map in ui.rows().iter().flatten() {
+        let (x, y, width, height) = ui.key_rect(map.key).unwrap();
+        assert!(x + width <= ui.number_block_start() as f64);
+        assert_eq!(
+            ui.get_key((x + width / 2.0, y + height / 2.0)),
+            Some(map.key)
+        );
     }
 }
@@ -0,0 +1,176 @@
+use cairo::Context;
+
+use crate::{consts::EXCULDE_ZONE_TOP, emoji::EmojiCategory, otherkeys};
+
+/// The rows of symbols, the row below them holds the keys of the panel
+const SYMBOL_ROWS: usize = 3;
+
+const RECENT_LABEL: &str = "🕘";
+
+/// The pages of symbols shown instead of the main keys, the first category is the recent one
+#[derive(Debug, Default)]
+pub(super) struct EmojiPanel {
+    categories: Vec<EmojiCategory>,
+    category: usize,
+    page: usize,
+}
+
+impl EmojiPanel {
+    pub fn new(categories: Vec<EmojiCategory>) -> Self {
+        let recent = EmojiCategory {
+            label: RECENT_LABEL.to_string(),
+            symbols: vec![],
+        };
+        EmojiPanel {
+            categories: std::iter::once(recent).chain(categories).collect(),
+            category: 0,
+            page: 0,
+        }
+    }
+
+    pub fn set_recent(&mut self, symbols: Vec<String>) {
+        if let Some(recent) = self.categories.first_mut() {
+            recent.symbols = symbols;
+        }
+    }
+
+    /// Open on the recent symbols, or the first category while there are none
+    pub fn reset(&mut self) {
+        let no_recent = self
+            .categories
+            .first()
+            .is_some_and(|recent| recent.symbols.is_empty());
+        self.category = usize::from(no_recent && self.categories.len() > 1);
+        self.page = 0;
+    }
+
+    /// Returns true if the category changed
+    pub fn set_category(&mut self, key: u32) -> bool {
+        let Some(index) = key.checked_sub(otherkeys::EMOJI_CATEGORY_START) else {
+            return false;
+        };
+        let index = index as usize;
+        if index >= self.categories.len() || index == self.category {
+            return false;
+        }
+        self.category = index;
+        self.page = 0;
+        true
+    }
+
+    fn symbols(&self) -> &[String] {
+        self.categories
+            .get(self.category)
+            .map(|category| category.symbols.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn page_size(columns: usize) -> usize {
+        (SYMBOL_ROWS * columns).max(1)
+    }
+
+    /// Go to the next or the previous page, around at the ends
+    pub fn flip(&mut self, forward: bool, columns: usize) {
+        let pages = self
+            .symbols()
+            .len()
+            .div_ceil(Self::page_size(columns))
+            .max(1);
+        self.page = if forward {
+            (self.page + 1) % pages
+        } else {
+            (self.page + pages - 1) % pages
+        };
+    }
+
+    fn page_symbols(&self, columns: usize) -> &[String] {
+        let symbols = self.symbols();
+        let start = (self.page * Self::page_size(columns)).min(symbols.len());
+        let end = (start + Self::page_size(columns)).min(symbols.len());
+        &symbols[start..end]
+    }
+
+    pub fn symbol(&self, key: u32, columns: usize) -> Option<&str> {
+        let index = key.checked_sub(otherkeys::EMOJI_START)?;
+        self.page_symbols(columns)
+            .get(index as usize)
+            .map(String::as_str)
+    }
+
+    /// The keys of the bottom row with their width in cells
+    fn bottom_keys(&self) -> Vec<(u32, &str, usize)> {
+        let categories = self.categories.iter().enumerate().map(|(index, category)| {
+            let key = otherkeys::EMOJI_CATEGORY_START + index as u32;
+            (key, category.label.as_str(), 1)
+        });
+        std::iter::once((otherkeys::EMOJI_PANEL, "ABC", 2))
+            .chain(categories)
+            .chain([
+                (otherkeys::EMOJI_PREV_PAGE, "◀", 1),
+                (otherkeys::EMOJI_NEXT_PAGE, "▶", 1),
+                (otherkeys::BACKSPACE, "Back", 2),
+            ])
+            .collect()
+    }
+
+    /// The width of a cell of the bottom row, the row fits under the symbols
+    fn bottom_cell(&self, step: f64, columns: usize) -> f64 {
+        let cells: usize = self.bottom_keys().iter().map(|(_, _, width)| width).sum();
+        step.min(step * columns as f64 / cells.max(1) as f64)
+    }
+
+    /// The symbol or the panel key at the position
+    pub fn find(&self, (pos_x, pos_y): (f64, f64), step: f64, columns: usize) -> Option<u32> {
+        let row = ((pos_y - EXCULDE_ZONE_TOP) / step) as usize;
+        let column = (pos_x / step) as usize;
+        if row < SYMBOL_ROWS {
+            let index = row * columns + column;
+            return (column < columns && index < self.page_symbols(columns).len())
+                .then_some(otherkeys::EMOJI_START + index as u32);
+        }
+        let cell = self.bottom_cell(step, columns);
+        let mut start = 0.0;
+        for (key, _, width) in self.bottom_keys() {
+            start += cell * width as f64;
+            if pos_x < start {
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    pub fn draw(&self, content: &Context, pangolayout: &pango::Layout, step: f64, columns: usize) {
+        let cell = |x: f64, y: f64, width: f64, text: &str| {
+            content.rectangle(x, y, width, step);
+            content.stroke().unwrap();
+            pangolayout.set_text(text);
+            let (textwidth, textheight) = pangolayout.pixel_size();
+            content.save().unwrap();
+            content.move_to(
+                x + (width - textwidth as f64) / 2.0,
+                y + (step - textheight as f64) / 2.0,
+            );
+            pangocairo::show_layout(content, pangolayout);
+            content.restore().unwrap();
+        };
+        for (index, symbol) in self.page_symbols(columns).iter().enumerate() {
+            let x = step * (index % columns) as f64;
+            let y = step * (index / columns) as f64 + EXCULDE_ZONE_TOP;
+            cell(x, y, step, symbol);
+        }
+        let y = step * SYMBOL_ROWS as f64 + EXCULDE_ZONE_TOP;
+        let bottom_cell = self.bottom_cell(step, columns);
+        let mut x = 0.0;
+        for (key, label, width) in self.bottom_keys() {
+            let width = bottom_cell * width as f64;
+            if key == otherkeys::EMOJI_CATEGORY_START + self.category as u32 {
+                content.rectangle(x, y, width, step);
+                content.set_source_rgb(0.75, 0.75, 0.75);
+                content.fill().unwrap();
+                content.set_source_rgb(0.0, 0.0, 0.0);
+            }
+            cell(x, y, width, label);
+            x += width;
+        }
+    }
+}
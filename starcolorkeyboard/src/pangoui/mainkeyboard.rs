@@ -1,37 +1,10 @@
 use cairo::Context;
 
-use serde::{Deserialize, Serialize};
-
-use std::sync::OnceLock;
-
 use crate::KeyModifierType;
 
-use crate::consts::EXCULDE_ZONE_TOP;
-
-use super::contain_mode;
-
-static MAIN_LAYOUT_INFO: OnceLock<Vec<Vec<MainLayout>>> = OnceLock::new();
+use crate::{consts::EXCULDE_ZONE_TOP, keyboardlayouts::LayoutKey};
 
-const MAIN_LAYOUT: &str = include_str!("../../asserts/mainkeylayout/enUS.json");
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct MainLayout {
-    text: String,
-    cap: Option<String>,
-    shift: Option<String>,
-    width: usize,
-    line: usize,
-    start_pos: usize,
-    key: usize,
-}
-
-// TODO: cap and shift
-#[derive(Clone, Copy)]
-enum KeyType {
-    Normal,
-    Cap,
-    Shift,
-}
+use super::{contain_mode, get_key_text, outside_clip, Highlight};
 
 fn contain_mode_special(keymode: KeyModifierType, key_type: KeyModifierType) -> bool {
     if key_type == KeyModifierType::NoMod {
@@ -40,71 +13,39 @@ fn contain_mode_special(keymode: KeyModifierType, key_type: KeyModifierType) ->
     contain_mode(keymode, key_type)
 }
 
-impl MainLayout {
-    fn get_info(&self, keymode: KeyModifierType, step: f64, font_size: i32) -> DrawInfo<'_> {
-        let layout_keytype: KeyModifierType = self.key.into();
-        let locked = contain_mode_special(keymode, layout_keytype);
-        let keytype = keymode.into();
-        match keytype {
-            KeyType::Normal => DrawInfo {
-                step,
-                width: self.width as i32,
-                font_size,
-                line: self.line as i32,
-                text: self.text.as_str(),
-                start_pos: self.start_pos as i32,
-                locked,
-            },
-            KeyType::Cap => DrawInfo {
-                step,
-                width: self.width as i32,
-                font_size,
-                line: self.line as i32,
-                text: match &self.cap {
-                    Some(text) => text,
-                    None => self.text.as_str(),
-                },
-                start_pos: self.start_pos as i32,
-                locked,
-            },
-            KeyType::Shift => DrawInfo {
-                step,
-                width: self.width as i32,
-                font_size,
-                line: self.line as i32,
-                text: match &self.shift {
-                    Some(text) => text,
-                    None => match &self.cap {
-                        Some(text) => text,
-                        None => self.text.as_str(),
-                    },
-                },
-                start_pos: self.start_pos as i32,
-                locked,
-            },
-        }
-    }
-}
-
-impl From<KeyModifierType> for KeyType {
-    fn from(value: KeyModifierType) -> Self {
-        if contain_mode(value, KeyModifierType::Shift) {
-            KeyType::Shift
-        } else if contain_mode(value, KeyModifierType::CapsLock) {
-            KeyType::Cap
-        } else {
-            KeyType::Normal
-        }
-    }
+/// The label, and if the key is drawn active and locked
+pub(super) fn key_look<'a>(
+    map: &'a LayoutKey,
+    highlight: &Highlight,
+    altgr: bool,
+) -> (&'a str, bool, bool) {
+    let layout_keytype = KeyModifierType::from_key(map.key, altgr);
+    (
+        get_key_text(map, highlight.key_type),
+        contain_mode_special(highlight.key_type, layout_keytype)
+            || highlight.pressed.contains(&map.key),
+        contain_mode_special(highlight.locked, layout_keytype),
+    )
 }
 
-fn get_main_layout() -> Vec<Vec<MainLayout>> {
-    if let Some(layout_info) = MAIN_LAYOUT_INFO.get() {
-        layout_info.clone()
-    } else {
-        let layout: Vec<Vec<MainLayout>> = serde_json::from_str(MAIN_LAYOUT).unwrap();
-        MAIN_LAYOUT_INFO.set(layout.clone()).expect("Cannot set it");
-        layout
+fn get_info<'a>(
+    map: &'a LayoutKey,
+    line: usize,
+    highlight: &Highlight,
+    altgr: bool,
+    step: f64,
+    font_size: i32,
+) -> DrawInfo<'a> {
+    let (text, active, locked) = key_look(map, highlight, altgr);
+    DrawInfo {
+        step,
+        width: map.width as i32,
+        font_size,
+        line: line as i32,
+        text,
+        start_pos: map.start_pos as i32,
+        active,
+        locked,
     }
 }
 
@@ -115,6 +56,7 @@ struct DrawInfo<'a> {
     line: i32,
     text: &'a str,
     start_pos: i32,
+    active: bool,
     locked: bool,
 }
 
@@ -128,6 +70,7 @@ fn draw_unit_key(
         line,
         text,
         start_pos,
+        active,
         locked,
     }: DrawInfo,
 ) {
@@ -136,9 +79,11 @@ fn draw_unit_key(
     let end_x = step * width as f64 / 2.0 + start_x;
     let start_y = step * line as f64 + exclude_zone;
     let end_y = step * (line + 1) as f64 + exclude_zone;
-    if locked {
+    if active {
+        // NOTE: a locked modifier is darker than a held or latched one
+        let grey = if locked { 0.5 } else { 0.75 };
         content.rectangle(start_x, start_y, end_x - start_x, end_y - start_y);
-        content.set_source_rgb(0.5, 0.5, 0.5);
+        content.set_source_rgb(grey, grey, grey);
         content.fill().unwrap();
         content.set_source_rgb(0_f64, 0_f64, 0_f64);
     }
@@ -165,33 +110,39 @@ fn draw_unit_key(
 pub(crate) fn draw_main_keyboard(
     content: &Context,
     pangolayout: &pango::Layout,
-    height: i32,
+    main_layout: &[Vec<LayoutKey>],
+    altgr: bool,
+    step: i32,
     font_size: i32,
-    key_type: KeyModifierType,
+    highlight: &Highlight,
 ) {
-    let exclude_zone = EXCULDE_ZONE_TOP;
-    let step = (height - exclude_zone as i32) / 4;
-
-    for oneline in get_main_layout().iter() {
+    for (line, oneline) in main_layout.iter().enumerate() {
+        let y = step as f64 * line as f64 + EXCULDE_ZONE_TOP;
+        if outside_clip(content, y, step as f64) {
+            continue;
+        }
         for map in oneline.iter() {
             draw_unit_key(
                 pangolayout,
                 content,
-                map.get_info(key_type, step as f64, font_size),
+                get_info(map, line, highlight, altgr, step as f64, font_size),
             );
         }
     }
 }
 
-pub fn find_keycode_from_mainkeyboard((pos_x, pos_y): (i32, i32), step: i32) -> Option<u32> {
+pub(super) fn find_keycode_from_mainkeyboard(
+    main_layout: &[Vec<LayoutKey>],
+    (pos_x, pos_y): (i32, i32),
+    step: i32,
+) -> Option<u32> {
     let exclude_zone = EXCULDE_ZONE_TOP;
-    let main_layout = get_main_layout();
     let aby = (pos_y - exclude_zone as i32) / step;
     if aby >= main_layout.len() as i32 {
         return None;
     }
     for map in main_layout[aby as usize].iter() {
-        let MainLayout {
+        let LayoutKey {
             width,
             start_pos,
             key,
@@ -200,7 +151,7 @@ pub fn find_keycode_from_mainkeyboard((pos_x, pos_y): (i32, i32), step: i32) ->
         if pos_x > *start_pos as i32 * step / 2
             && pos_x < (*start_pos as i32 + *width as i32) * step / 2
         {
-            return Some(*key as u32);
+            return Some(*key);
         }
     }
     None
@@ -0,0 +1,114 @@
+use cairo::Context;
+
+use crate::consts::EXCULDE_ZONE_TOP;
+
+use super::fill_background;
+
+/// The row of alternates shown over a held key
+#[derive(Debug)]
+pub(super) struct Popup {
+    /// the held key
+    pub key: u32,
+    pub alternates: Vec<String>,
+    x: f64,
+    y: f64,
+    step: f64,
+}
+
+impl Popup {
+    /// Above the key if there is a row above it, else below it, and always inside the width
+    pub fn new(
+        key: u32,
+        alternates: Vec<String>,
+        (key_x, key_y, step): (f64, f64, f64),
+        width: i32,
+    ) -> Self {
+        let popup_width = step * alternates.len() as f64;
+        let x = key_x.min(width as f64 - popup_width).max(0.0);
+        let y = if key_y - step >= EXCULDE_ZONE_TOP {
+            key_y - step
+        } else {
+            key_y + step
+        };
+        Popup {
+            key,
+            alternates,
+            x,
+            y,
+            step,
+        }
+    }
+
+    /// The index of the alternate at the position
+    pub fn find(&self, (pos_x, pos_y): (f64, f64)) -> Option<usize> {
+        if pos_y < self.y || pos_y >= self.y + self.step || pos_x < self.x {
+            return None;
+        }
+        let index = ((pos_x - self.x) / self.step) as usize;
+        (index < self.alternates.len()).then_some(index)
+    }
+}
+
+/// The label of the held key over it in a bigger font, the finger covers the key
+pub(super) fn draw_bubble(
+    content: &Context,
+    pangolayout: &pango::Layout,
+    (x, y, width, height): (f64, f64, f64, f64),
+    text: &str,
+) {
+    content.rectangle(x, y, width, height);
+    fill_background(content);
+    content.set_source_rgb(0.0, 0.0, 0.0);
+    content.stroke().unwrap();
+
+    let mut desc = pangolayout.font_description().unwrap_or_default();
+    let font_size = desc.size();
+    desc.set_size(font_size * 3 / 2);
+    pangolayout.set_font_description(Some(&desc));
+    pangolayout.set_text(text);
+    let (textwidth, textheight) = pangolayout.pixel_size();
+    content.save().unwrap();
+    content.move_to(
+        x + (width - textwidth as f64) / 2.0,
+        y + (height - textheight as f64) / 2.0,
+    );
+    pangocairo::show_layout(content, pangolayout);
+    content.restore().unwrap();
+    desc.set_size(font_size);
+    pangolayout.set_font_description(Some(&desc));
+}
+
+pub(super) fn draw_popup(
+    content: &Context,
+    pangolayout: &pango::Layout,
+    Popup {
+        alternates,
+        x,
+        y,
+        step,
+        ..
+    }: &Popup,
+) {
+    let (x, y, step) = (*x, *y, *step);
+    content.rectangle(x, y, step * alternates.len() as f64, step);
+    fill_background(content);
+    content.set_source_rgb(0.0, 0.0, 0.0);
+    for index in 1..alternates.len() {
+        let cell_x = x + step * index as f64;
+        content.move_to(cell_x, y);
+        content.line_to(cell_x, y + step);
+    }
+    content.stroke().unwrap();
+
+    for (index, alternate) in alternates.iter().enumerate() {
+        pangolayout.set_text(alternate);
+        let (textwidth, textheight) = pangolayout.pixel_size();
+        content.save().unwrap();
+        content.move_to(
+            x + step * index as f64 + (step - textwidth as f64) / 2.0,
+            y + (step - textheight as f64) / 2.0,
+        );
+        pangocairo::show_layout(content, pangolayout);
+        content.restore().unwrap();
+    }
+}
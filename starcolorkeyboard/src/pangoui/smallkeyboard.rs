@@ -2,228 +2,129 @@ use cairo::Context;
 
 use crate::consts::{EXCULDE_ZONE_RIGHT, EXCULDE_ZONE_TOP};
 
-use super::contain_mode;
-use crate::KeyModifierType;
+use super::{get_key_text, outside_clip, Highlight};
+use crate::keyboardlayouts::LayoutKey;
+
+/// The number of square keys in the widest row of the block
+pub(super) fn number_columns(numbers: &[Vec<LayoutKey>]) -> i32 {
+    let half_steps = numbers
+        .iter()
+        .flatten()
+        .map(|key| key.start_pos + key.width)
+        .max()
+        .unwrap_or(0);
+    (half_steps as i32 + 1) / 2
+}
 
-fn contain_shift(key_type: KeyModifierType) -> bool {
-    contain_mode(key_type, KeyModifierType::Shift)
+/// The size of the square keys of the blocks side by side, the rows of the highest block fill
+/// the height unless the blocks take more than half of the width then. Wide blocks or a narrow
+/// output get smaller keys
+pub(super) fn number_step(blocks: &[&[Vec<LayoutKey>]], width: i32, height: i32) -> f64 {
+    let rows = blocks.iter().map(|block| block.len()).max().unwrap_or(0);
+    let step = (height as f64 - EXCULDE_ZONE_TOP) / rows.max(1) as f64;
+    let columns: i32 = blocks.iter().map(|block| number_columns(block)).sum();
+    step.min(width as f64 / 2.0 / columns.max(1) as f64)
 }
 
-pub fn find_keycode_from_smallkeyboard((pos_x, pos_y): (i32, i32), start_x: i32, step: i32) -> u32 {
+pub(super) fn find_keycode_from_smallkeyboard(
+    numbers: &[Vec<LayoutKey>],
+    (pos_x, pos_y): (i32, i32),
+    start_x: i32,
+    step: i32,
+) -> Option<u32> {
     let exclude_zone = EXCULDE_ZONE_TOP as i32;
-    let abx = (pos_x - start_x) / step;
     let aby = (pos_y - exclude_zone) / step;
-    let code = aby * 3 + abx + 2;
-    code as u32
+    let row = numbers.get(aby as usize)?;
+    let pos_x = pos_x - start_x;
+    row.iter()
+        .find(|key| {
+            pos_x >= key.start_pos as i32 * step / 2
+                && pos_x < (key.start_pos + key.width) as i32 * step / 2
+        })
+        .map(|key| key.key)
 }
 
-fn draw_extra_btn(content: &Context, pangolayout: &pango::Layout, width: i32, font_size: i32) {
-    let step = EXCULDE_ZONE_RIGHT / 2.0;
-    let x_1 = width as f64 - EXCULDE_ZONE_RIGHT;
-    let x_2 = width as f64 - step;
-    let x_3 = width as f64;
+pub(super) fn draw_extra_btn(
+    content: &Context,
+    pangolayout: &pango::Layout,
+    width: i32,
+    labels: &[&str],
+) {
+    let step = EXCULDE_ZONE_TOP;
+    let x_start = width as f64 - EXCULDE_ZONE_RIGHT;
+    let x_end = width as f64;
     let y_1 = 0.0;
     let y_2 = step;
     content.set_source_rgb(0.0, 0.0, 0.0);
-    content.move_to(x_1, y_1);
-    content.line_to(x_1, y_2);
-    content.move_to(x_2, y_1);
-    content.line_to(x_2, y_2);
-    content.move_to(x_3, y_1);
-    content.line_to(x_3, y_2);
+    for index in 0..=labels.len() {
+        let x = x_start + step * index as f64;
+        content.move_to(x, y_1);
+        content.line_to(x, y_2);
+    }
 
-    content.move_to(x_1, y_1);
-    content.line_to(x_3, y_1);
-    content.move_to(x_1, y_2);
-    content.line_to(x_3, y_2);
+    content.move_to(x_start, y_1);
+    content.line_to(x_end, y_1);
+    content.move_to(x_start, y_2);
+    content.line_to(x_end, y_2);
 
     content.stroke().unwrap();
 
-    let font_adjustx = step / 2.0 - font_size as f64 / 2.0;
-    pangolayout.set_text("-");
-    content.save().unwrap();
-    content.move_to(x_1 + font_adjustx, y_1);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    pangolayout.set_text("x");
-    content.save().unwrap();
-    content.move_to(x_2 + font_adjustx, y_1);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
+    for (index, label) in labels.iter().enumerate() {
+        pangolayout.set_text(label);
+        let (textwidth, _) = pangolayout.pixel_size();
+        let font_adjustx = ((step - textwidth as f64) / 2.0).max(0.0);
+        content.save().unwrap();
+        content.move_to(x_start + step * index as f64 + font_adjustx, y_1);
+        pangocairo::show_layout(content, pangolayout);
+        content.restore().unwrap();
+    }
 }
 
 pub(super) fn draw_number_keyboard(
     content: &Context,
     pangolayout: &pango::Layout,
-    width: i32,
-    height: i32,
+    numbers: &[Vec<LayoutKey>],
+    start_x: f64,
+    step: f64,
     font_size: i32,
-    key_type: KeyModifierType,
+    highlight: &Highlight,
 ) {
-    // NOTE: here require width > height
-    assert!(width - EXCULDE_ZONE_RIGHT as i32 > height);
-
-    let exclude_zone = EXCULDE_ZONE_RIGHT / 2.0;
-
-    let step = (height as f64 - exclude_zone) / 3.0;
-    let x_1 = width as f64 - 4.0 * step;
-    let x_2 = width as f64 - 3.0 * step;
-    let x_3 = width as f64 - 2.0 * step;
-    let x_4 = width as f64 - step;
-    let x_5 = width as f64;
-
-    let y_1 = 0.0 + exclude_zone;
-    let y_2 = step + exclude_zone;
-    let y_3 = 2.0 * step + exclude_zone;
-    let y_4 = height as f64;
+    let exclude_zone = EXCULDE_ZONE_TOP;
 
     let font_adjusty = step / 2.0 - font_size as f64;
     let font_adjustx = step / 2.0 - font_size as f64 / 2.0;
     content.set_source_rgb(0.0, 0.0, 0.0);
-    content.move_to(x_1, y_1);
-    content.line_to(x_1, y_4);
-    content.move_to(x_2, y_1);
-    content.line_to(x_2, y_4);
-    content.move_to(x_3, y_1);
-    content.line_to(x_3, y_4);
-    content.move_to(x_4, y_1);
-    content.line_to(x_4, y_4);
-    content.move_to(x_5, y_1);
-    content.line_to(x_5, y_4);
-
-    content.move_to(x_1, y_1);
-    content.line_to(x_5, y_1);
-    content.move_to(x_1, y_2);
-    content.line_to(x_5, y_2);
-    content.move_to(x_1, y_3);
-    content.line_to(x_5, y_3);
-    content.move_to(x_1, y_4);
-    content.line_to(x_5, y_4);
-
-    content.stroke().unwrap();
-
-    let shiftmode = contain_shift(key_type);
-
-    if shiftmode {
-        pangolayout.set_text("!");
-    } else {
-        pangolayout.set_text("1");
+    for (line, row) in numbers.iter().enumerate() {
+        let y_1 = step * line as f64 + exclude_zone;
+        let y_2 = y_1 + step;
+        if outside_clip(content, y_1, step) {
+            continue;
+        }
+        for key in row.iter() {
+            let x_1 = start_x + step * key.start_pos as f64 / 2.0;
+            let x_2 = x_1 + step * key.width as f64 / 2.0;
+            if highlight.pressed.contains(&key.key) {
+                content.rectangle(x_1, y_1, x_2 - x_1, y_2 - y_1);
+                content.set_source_rgb(0.75, 0.75, 0.75);
+                content.fill().unwrap();
+                content.set_source_rgb(0.0, 0.0, 0.0);
+            }
+            content.move_to(x_1, y_1);
+            content.line_to(x_1, y_2);
+            content.move_to(x_2, y_1);
+            content.line_to(x_2, y_2);
+
+            content.move_to(x_1, y_1);
+            content.line_to(x_2, y_1);
+            content.move_to(x_1, y_2);
+            content.line_to(x_2, y_2);
+            content.stroke().unwrap();
+
+            pangolayout.set_text(get_key_text(key, highlight.key_type));
+            content.save().unwrap();
+            content.move_to(x_1 + font_adjustx, y_1 + font_adjusty);
+            pangocairo::show_layout(content, pangolayout);
+            content.restore().unwrap();
+        }
     }
-    content.save().unwrap();
-    content.move_to(x_1 + font_adjustx, y_1 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    if shiftmode {
-        pangolayout.set_text("@");
-    } else {
-        pangolayout.set_text("2");
-    }
-    content.save().unwrap();
-    content.move_to(x_2 + font_adjustx, y_1 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    if shiftmode {
-        pangolayout.set_text("#");
-    } else {
-        pangolayout.set_text("3");
-    }
-    content.save().unwrap();
-    content.move_to(x_3 + font_adjustx, y_1 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    if shiftmode {
-        pangolayout.set_text("$");
-    } else {
-        pangolayout.set_text("4");
-    }
-    content.save().unwrap();
-    content.move_to(x_1 + font_adjustx, y_2 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    if shiftmode {
-        pangolayout.set_text("%");
-    } else {
-        pangolayout.set_text("5");
-    }
-    content.save().unwrap();
-    content.move_to(x_2 + font_adjustx, y_2 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    if shiftmode {
-        pangolayout.set_text("^");
-    } else {
-        pangolayout.set_text("6");
-    }
-    content.save().unwrap();
-    content.move_to(x_3 + font_adjustx, y_2 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    if shiftmode {
-        pangolayout.set_text("&");
-    } else {
-        pangolayout.set_text("7");
-    }
-    content.save().unwrap();
-    content.move_to(x_1 + font_adjustx, y_3 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    if shiftmode {
-        pangolayout.set_text("*");
-    } else {
-        pangolayout.set_text("8");
-    }
-    content.save().unwrap();
-    content.move_to(x_2 + font_adjustx, y_3 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    if shiftmode {
-        pangolayout.set_text("(");
-    } else {
-        pangolayout.set_text("9");
-    }
-    content.save().unwrap();
-    content.move_to(x_3 + font_adjustx, y_3 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    if shiftmode {
-        pangolayout.set_text("_");
-    } else {
-        pangolayout.set_text("-");
-    }
-    content.save().unwrap();
-    content.move_to(x_4 + font_adjustx, y_1 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    if shiftmode {
-        pangolayout.set_text(")");
-    } else {
-        pangolayout.set_text("0");
-    }
-    content.save().unwrap();
-    content.move_to(x_4 + font_adjustx, y_2 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    if shiftmode {
-        pangolayout.set_text("+");
-    } else {
-        pangolayout.set_text("=");
-    }
-    content.save().unwrap();
-    content.move_to(x_4 + font_adjustx, y_3 + font_adjusty);
-    pangocairo::show_layout(content, pangolayout);
-    content.restore().unwrap();
-
-    draw_extra_btn(content, pangolayout, width, font_size);
 }
@@ -0,0 +1,407 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config, consts::SUGGESTION_COUNT};
+
+/// Days after which a learned word counts half as much
+const HALF_LIFE_DAYS: f64 = 30.0;
+
+/// Learned words below this score are dropped when the user dictionary is saved
+const MIN_SCORE: f64 = 0.05;
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+/// The user dictionary is written once nothing was learned or forgotten for this long
+pub const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+/// Words with their frequency, sorted by the lowercase word to find the prefixes fast
+#[derive(Debug, Default)]
+pub struct Dictionary {
+    words: Vec<(String, u64)>,
+}
+
+impl Dictionary {
+    /// One `word frequency` pair per line, a missing frequency is 1, `#` starts a comment line.
+    /// A line with a frequency which is no number is reported and skipped
+    pub fn parse(content: &str, path: &Path) -> Self {
+        let mut words: Vec<(String, u64)> = content
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|(number, line)| {
+                let mut fields = line.split_whitespace();
+                let word = fields.next()?.to_lowercase();
+                let frequency = match fields.next().map(str::parse) {
+                    None => 1,
+                    Some(Ok(frequency)) => frequency,
+                    Some(Err(err)) => {
+                        eprintln!(
+                            "{}:{number}: frequency {err}, skip the line",
+                            path.display()
+                        );
+                        return None;
+                    }
+                };
+                Some((word, frequency))
+            })
+            .collect();
+        words.sort();
+        words.dedup_by(|later, first| {
+            if later.0 != first.0 {
+                return false;
+            }
+            first.1 = first.1.max(later.1);
+            true
+        });
+        Dictionary { words }
+    }
+
+    /// The dictionary of the xkb layout, empty if there is no file for it
+    pub fn load(layout_name: &str) -> Self {
+        for dir in config::dictionary_dirs() {
+            let path = dir.join(format!("{layout_name}.txt"));
+            if let Some(dictionary) = Self::read(&path) {
+                return dictionary;
+            }
+        }
+        Dictionary::default()
+    }
+
+    fn read(path: &Path) -> Option<Self> {
+        if !path.is_file() {
+            return None;
+        }
+        match std::fs::read_to_string(path) {
+            Ok(content) => Some(Self::parse(&content, path)),
+            Err(err) => {
+                eprintln!("{}: {err}, skip it", path.display());
+                None
+            }
+        }
+    }
+
+    /// The words starting with the prefix, the most frequent first
+    fn matches(&self, prefix: &str) -> Vec<&str> {
+        let lower = prefix.to_lowercase();
+        let start = self.words.partition_point(|(word, _)| *word < lower);
+        let mut matches: Vec<&(String, u64)> = self.words[start..]
+            .iter()
+            .take_while(|(word, _)| word.starts_with(&lower))
+            .collect();
+        matches.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        matches.into_iter().map(|(word, _)| word.as_str()).collect()
+    }
+}
+
+/// The word with a capital first letter if the prefix has one
+fn capitalize_like(prefix: &str, word: &str) -> String {
+    if !prefix.chars().next().is_some_and(char::is_uppercase) {
+        return word.to_string();
+    }
+    let mut chars = word.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// A learned word, its score halves every [HALF_LIFE_DAYS] it is not typed
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Learned {
+    score: f64,
+    /// seconds since the epoch when the score was last updated
+    updated: u64,
+}
+
+impl Learned {
+    fn score_at(self, time: u64) -> f64 {
+        let days = time.saturating_sub(self.updated) as f64 / SECONDS_PER_DAY;
+        self.score * 0.5_f64.powf(days / HALF_LIFE_DAYS)
+    }
+
+    /// Both scores together at the later update
+    fn merge(self, other: Learned) -> Learned {
+        let updated = self.updated.max(other.updated);
+        Learned {
+            score: self.score_at(updated) + other.score_at(updated),
+            updated,
+        }
+    }
+}
+
+/// The words learned from typing, kept in lowercase, and the words the user wants to never see.
+/// The suggestions take the case of the typed prefix
+#[derive(Debug, Default)]
+pub struct UserDictionary {
+    /// None keeps the dictionary in memory only
+    path: Option<PathBuf>,
+    words: BTreeMap<String, Learned>,
+    forgotten: BTreeSet<String>,
+    /// changed since the file was written
+    dirty: bool,
+}
+
+impl UserDictionary {
+    /// One `word score updated` line per learned word, one `-word` line per forgotten word
+    fn parse(content: &str) -> (BTreeMap<String, Learned>, BTreeSet<String>) {
+        let mut words = BTreeMap::new();
+        let mut forgotten = BTreeSet::new();
+        for line in content.lines().map(str::trim) {
+            if let Some(word) = line.strip_prefix('-') {
+                forgotten.insert(word.to_lowercase());
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(word), Some(score), Some(updated)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let (Ok(score), Ok(updated)) = (score.parse(), updated.parse()) else {
+                continue;
+            };
+            // NOTE: older files kept the words as typed, the cases of a word count together
+            let learned = Learned { score, updated };
+            words
+                .entry(word.to_lowercase())
+                .and_modify(|known: &mut Learned| *known = known.merge(learned))
+                .or_insert(learned);
+        }
+        (words, forgotten)
+    }
+
+    /// Read [config::user_dictionary_path], a missing file is an empty dictionary
+    pub fn load() -> Self {
+        let path = config::user_dictionary_path();
+        let (words, forgotten) = match std::fs::read_to_string(&path) {
+            Ok(content) => Self::parse(&content),
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    eprintln!(
+                        "{}: {err}, start with an empty user dictionary",
+                        path.display()
+                    );
+                }
+                Default::default()
+            }
+        };
+        UserDictionary {
+            path: Some(path),
+            words,
+            forgotten,
+            dirty: false,
+        }
+    }
+
+    fn to_content(&self, time: u64) -> String {
+        let mut content = String::new();
+        for (word, learned) in &self.words {
+            let score = learned.score_at(time);
+            if score >= MIN_SCORE {
+                content.push_str(&format!("{word} {score} {time}\n"));
+            }
+        }
+        for word in &self.forgotten {
+            content.push_str(&format!("-{word}\n"));
+        }
+        content
+    }
+
+    /// Write the file if anything changed since it was written
+    pub fn save(&mut self) {
+        if !std::mem::take(&mut self.dirty) {
+            return;
+        }
+        let Some(path) = self.path.as_ref() else {
+            return;
+        };
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            // NOTE: write the whole file aside first, a crash never leaves half of it
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, self.to_content(now()))?;
+            std::fs::rename(&tmp, path)
+        };
+        if let Err(err) = write() {
+            eprintln!("{}: {err}, the learned words are not saved", path.display());
+        }
+    }
+
+    fn learn_at(&mut self, word: &str, time: u64) {
+        let lower = word.to_lowercase();
+        self.forgotten.remove(&lower);
+        let score = self
+            .words
+            .get(&lower)
+            .map(|learned| learned.score_at(time))
+            .unwrap_or_default();
+        self.words.insert(
+            lower,
+            Learned {
+                score: score + 1.0,
+                updated: time,
+            },
+        );
+        self.dirty = true;
+    }
+
+    /// Count the typed word, a forgotten word typed again is learned again.
+    /// Nothing is written until [UserDictionary::save]
+    pub fn learn(&mut self, word: &str) {
+        self.learn_at(word, now());
+    }
+
+    /// Never suggest the word again, until it is typed again
+    pub fn forget(&mut self, word: &str) {
+        let lower = word.to_lowercase();
+        self.words.remove(&lower);
+        self.forgotten.insert(lower);
+        self.dirty = true;
+    }
+
+    fn is_forgotten(&self, word: &str) -> bool {
+        self.forgotten.contains(&word.to_lowercase())
+    }
+
+    /// The learned words starting with the prefix, the highest score first
+    fn complete_at(&self, prefix: &str, time: u64) -> Vec<&str> {
+        let lower = prefix.to_lowercase();
+        let mut matches: Vec<(&str, f64)> = self
+            .words
+            .iter()
+            .filter(|(word, _)| word.starts_with(&lower))
+            .map(|(word, learned)| (word.as_str(), learned.score_at(time)))
+            .collect();
+        matches.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        matches.into_iter().map(|(word, _)| word).collect()
+    }
+}
+
+/// The dictionary of the layout together with the words the user typed
+#[derive(Debug, Default)]
+pub struct Predictor {
+    pub dictionary: Dictionary,
+    pub user: UserDictionary,
+}
+
+impl Predictor {
+    /// The learned words come first, they are the ones the dictionary does not know well.
+    /// A capital prefix gives capital words
+    pub fn complete(&self, prefix: &str) -> Vec<String> {
+        if prefix.is_empty() {
+            return vec![];
+        }
+        let learned = self.user.complete_at(prefix, now());
+        let words = learned.into_iter().chain(self.dictionary.matches(prefix));
+        let mut suggestions: Vec<String> = vec![];
+        for word in words.map(|word| capitalize_like(prefix, word)) {
+            if suggestions.len() == SUGGESTION_COUNT {
+                break;
+            }
+            let duplicate = suggestions
+                .iter()
+                .any(|suggestion| suggestion.to_lowercase() == word.to_lowercase());
+            if !duplicate && !self.user.is_forgotten(&word) {
+                suggestions.push(word);
+            }
+        }
+        suggestions
+    }
+}
+
+/// Characters which continue a word, everything else ends it
+pub fn is_word_char(character: char) -> bool {
+    character.is_alphanumeric() || character == '\'' || character == '-'
+}
+
+#[test]
+fn tst_complete() {
+    let dictionary = Predictor {
+        dictionary: Dictionary::parse(
+            "# frequencies\nthe 23135851162\nthen 120\nthere 300\nthey 200\nThe 10\ntheir\n\
+             thence 12x\nzebra 5\n",
+            Path::new("test.txt"),
+        ),
+        user: UserDictionary::default(),
+    };
+    assert_eq!(dictionary.complete("the"), vec!["the", "there", "they"]);
+    assert_eq!(dictionary.complete("Then"), vec!["Then"]);
+    assert_eq!(dictionary.complete("x"), Vec::<String>::new());
+    assert_eq!(dictionary.complete(""), Vec::<String>::new());
+    assert_eq!(dictionary.complete("thei"), vec!["their"]);
+    // NOTE: the line with a broken frequency is skipped
+    assert_eq!(dictionary.complete("thenc"), Vec::<String>::new());
+}
+
+#[test]
+fn tst_user_dictionary() {
+    let day = SECONDS_PER_DAY as u64;
+    let mut user = UserDictionary::default();
+    user.learn_at("kubectl", 0);
+    user.learn_at("kubectl", 0);
+    user.learn_at("Kubernetes", 0);
+    assert_eq!(user.complete_at("kub", 0), vec!["kubectl", "kubernetes"]);
+    let learned = user.words["kubectl"];
+    assert_eq!(learned.score_at(HALF_LIFE_DAYS as u64 * day), 1.0);
+
+    // NOTE: a word typed now beats an older word typed more often
+    user.learn_at("kubelet", 90 * day);
+    assert_eq!(
+        user.complete_at("kube", 90 * day),
+        vec!["kubelet", "kubectl", "kubernetes"]
+    );
+
+    let (words, forgotten) = UserDictionary::parse(&user.to_content(90 * day));
+    assert_eq!(words.len(), 3);
+    assert!(forgotten.is_empty());
+    // NOTE: half a year later only the recent word is still worth keeping
+    let (words, _) = UserDictionary::parse(&user.to_content(180 * day));
+    assert_eq!(words.keys().collect::<Vec<_>>(), vec!["kubelet"]);
+
+    // NOTE: a file from before keeps the cases apart, they are one word now
+    let (words, _) = UserDictionary::parse("They 1 0\nthey 2 0\n");
+    assert_eq!(words["they"].score, 3.0);
+}
+
+#[test]
+fn tst_predictor() {
+    let mut predictor = Predictor {
+        dictionary: Dictionary::parse(
+            "the 500\nthere 300\nthey 200\nthen 100\n",
+            Path::new("test.txt"),
+        ),
+        user: UserDictionary::default(),
+    };
+    predictor.user.learn("thesaurus");
+    // NOTE: the word at the start of a sentence is the same word, it counts twice
+    predictor.user.learn("They");
+    predictor.user.learn("they");
+    assert_eq!(predictor.complete("the"), vec!["they", "thesaurus", "the"]);
+    assert_eq!(predictor.complete("The"), vec!["They", "Thesaurus", "The"]);
+    predictor.user.forget("they");
+    predictor.user.forget("the");
+    assert_eq!(
+        predictor.complete("the"),
+        vec!["thesaurus", "there", "then"]
+    );
+    let (_, forgotten) = UserDictionary::parse(&predictor.user.to_content(now()));
+    assert!(forgotten.contains("they"));
+    assert!(predictor.user.dirty);
+    predictor.user.save();
+    assert!(!predictor.user.dirty);
+    predictor.user.learn("the");
+    assert_eq!(predictor.complete("the"), vec!["the", "thesaurus", "there"]);
+}
@@ -0,0 +1,166 @@
+use std::{
+    ffi::CString,
+    fs::File,
+    os::unix::prelude::{AsRawFd, FromRawFd},
+};
+
+use memmap2::MmapOptions;
+use wayland_client::{
+    protocol::{wl_buffer, wl_shm, wl_shm_pool},
+    Dispatch, QueueHandle,
+};
+
+use crate::pangoui::Area;
+
+/// One buffer is read by the compositor while the next frame is drawn into the other
+const BUFFERS: i32 = 2;
+
+/// A wl_buffer cairo draws into, the shared memory is the data of its image surface
+#[derive(Debug)]
+pub struct ShmBuffer {
+    pub buffer: wl_buffer::WlBuffer,
+    pub surface: cairo::ImageSurface,
+    /// attached and not released yet, the compositor may read it
+    busy: bool,
+    /// the parts of the frames drawn into the other buffers since this one was drawn
+    damage: Vec<Area>,
+}
+
+impl ShmBuffer {
+    pub fn take_damage(&mut self) -> Vec<Area> {
+        std::mem::take(&mut self.damage)
+    }
+}
+
+/// The buffers of one size in one pool, a new size takes a new pool
+#[derive(Debug)]
+pub struct ShmPool {
+    pool: wl_shm_pool::WlShmPool,
+    buffers: Vec<ShmBuffer>,
+    size: (i32, i32),
+}
+
+/// Shared memory without a file on disk, a temporary file where there is no memfd
+fn shared_file() -> File {
+    let name = CString::new("starcolorkeyboard").unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        eprintln!(
+            "memfd_create failed, using a temporary file: {}",
+            std::io::Error::last_os_error()
+        );
+        return tempfile::tempfile().unwrap();
+    }
+    unsafe { File::from_raw_fd(fd) }
+}
+
+impl ShmPool {
+    pub fn new<D>(shm: &wl_shm::WlShm, (width, height): (i32, i32), qh: &QueueHandle<D>) -> Self
+    where
+        D: Dispatch<wl_shm_pool::WlShmPool, ()> + Dispatch<wl_buffer::WlBuffer, ()> + 'static,
+    {
+        // NOTE: cairo ARgb32 is wl_shm Argb8888, both premultiplied in native byte order
+        let stride = cairo::Format::ARgb32
+            .stride_for_width(width as u32)
+            .unwrap();
+        let size = stride * height;
+        let file = shared_file();
+        file.set_len((size * BUFFERS) as u64).unwrap();
+        let pool = shm.create_pool(file.as_raw_fd(), size * BUFFERS, qh, ());
+        let buffers = (0..BUFFERS)
+            .map(|index| {
+                let offset = size * index;
+                let mmap = unsafe {
+                    MmapOptions::new()
+                        .offset(offset as u64)
+                        .len(size as usize)
+                        .map_mut(&file)
+                }
+                .unwrap();
+                let surface = cairo::ImageSurface::create_for_data(
+                    mmap,
+                    cairo::Format::ARgb32,
+                    width,
+                    height,
+                    stride,
+                )
+                .unwrap();
+                let buffer = pool.create_buffer(
+                    offset,
+                    width,
+                    height,
+                    stride,
+                    wl_shm::Format::Argb8888,
+                    qh,
+                    (),
+                );
+                ShmBuffer {
+                    buffer,
+                    surface,
+                    busy: false,
+                    damage: vec![Area {
+                        x: 0,
+                        y: 0,
+                        width,
+                        height,
+                    }],
+                }
+            })
+            .collect();
+        // NOTE: the file closes here, the mappings and the pool of the compositor keep the memory
+        ShmPool {
+            pool,
+            buffers,
+            size: (width, height),
+        }
+    }
+
+    pub fn size(&self) -> (i32, i32) {
+        self.size
+    }
+
+    /// A buffer the compositor does not read to draw the next frame into, it is busy from now.
+    /// The `damage` of the frame is added to the buffers, None if all of them are busy
+    pub fn next_buffer(&mut self, damage: &[Area]) -> Option<&mut ShmBuffer> {
+        let index = self.buffers.iter().position(|buffer| !buffer.busy)?;
+        for buffer in self.buffers.iter_mut() {
+            buffer.damage.extend_from_slice(damage);
+        }
+        let buffer = &mut self.buffers[index];
+        buffer.busy = true;
+        Some(buffer)
+    }
+
+    /// The buffer is attached again, the compositor may read it until it is released again
+    pub fn attach(&mut self, attached: &wl_buffer::WlBuffer) {
+        if let Some(buffer) = self
+            .buffers
+            .iter_mut()
+            .find(|buffer| buffer.buffer == *attached)
+        {
+            buffer.busy = true;
+        }
+    }
+
+    /// The compositor is done with the buffer, false if it is not one of the pool
+    pub fn release(&mut self, released: &wl_buffer::WlBuffer) -> bool {
+        let buffer = self
+            .buffers
+            .iter_mut()
+            .find(|buffer| buffer.buffer == *released);
+        match buffer {
+            Some(buffer) => {
+                buffer.busy = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn destroy(self) {
+        for buffer in self.buffers {
+            buffer.buffer.destroy();
+        }
+        self.pool.destroy();
+    }
+}